@@ -0,0 +1,154 @@
+use crate::control::{self, StateRequest, StateResponse};
+use crate::persist::{self, State};
+use crate::types::{Config, InstancePolicy, PersistFormat};
+use chrono::{Local, TimeZone};
+use clap::Subcommand;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Inspect or modify the persisted module state
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateCommand {
+    /// Rewrite a state file in another format
+    Convert {
+        /// Format to write
+        #[arg(long)]
+        to: PersistFormat,
+        /// File to read, defaults to the configured persist path
+        #[arg(long)]
+        input: Option<String>,
+        /// File to write, defaults to the input file
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Print the saved data of every module, or just one
+    Show { module: Option<String> },
+    /// Forget everything saved for a module
+    Clear { module: String },
+    /// Set data keys of a module, as key=value pairs
+    Set {
+        module: String,
+        #[arg(required = true, value_parser = parse_pair)]
+        pairs: Vec<(String, String)>,
+    },
+}
+
+fn parse_pair(s: &str) -> StdResult<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got {}", s))
+}
+
+/// Sends the request to the running bar. `None` means nothing is listening on
+/// the control socket; once the request is sent, any failure is an error.
+fn ask_running(
+    socket: &str,
+    request: &StateRequest,
+) -> Option<StdResult<StateResponse, Box<dyn Error + Send + Sync>>> {
+    let mut stream = UnixStream::connect(socket).ok()?;
+    let mut exchange = || -> StdResult<StateResponse, Box<dyn Error + Send + Sync>> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        stream.write_all(&line)?;
+        let mut reply = String::new();
+        BufReader::new(&stream).read_line(&mut reply)?;
+        Ok(serde_json::from_str(&reply)?)
+    };
+    Some(exchange().map_err(|e| format!("no answer from the running bar: {}", e).into()))
+}
+
+/// Applies the request to the state file when no bar is listening. Changes
+/// are only made under the instance lock, so a bar whose control socket is
+/// missing can't overwrite them.
+fn edit_file(
+    request: StateRequest,
+    config: &Config,
+) -> StdResult<StateResponse, Box<dyn Error + Send + Sync>> {
+    let path = &config.persist.path;
+    let changes = !matches!(request, StateRequest::Show { .. });
+    let _lock = if changes {
+        let lock = persist::lock_instance(path, InstancePolicy::Exit)
+            .map_err(|e| format!("not changing {}: {}", path, e))?;
+        Some(lock)
+    } else {
+        None
+    };
+    let mut state = if Path::new(path).exists() {
+        persist::decode(&std::fs::read(path)?)?
+    } else {
+        State::new()
+    };
+    let known = |name: &str| config.modules.iter().any(|m| m.name == name);
+    let response = control::apply(request, &mut state, known);
+    if changes && response.is_ok() {
+        let format = config.persist.format.unwrap_or_default();
+        persist::save_state(path, &persist::encode(&state, format)?)?;
+    }
+    Ok(response)
+}
+
+fn print_state(state: &State) {
+    let mut names: Vec<&String> = state.keys().collect();
+    names.sort();
+    for name in names {
+        let meta = &state[name];
+        println!("{}", name);
+        let updated = Local
+            .timestamp_opt(meta.updated.as_secs() as i64, meta.updated.subsec_nanos())
+            .single()
+            .filter(|_| !meta.updated.is_zero())
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| String::from("never"));
+        println!("  updated: {}", updated);
+        let mut keys: Vec<&String> = meta.data.keys().collect();
+        keys.sort();
+        for key in keys {
+            println!("  {}: {}", key, meta.data[key]);
+        }
+    }
+}
+
+pub fn run(command: Command, config: &Config) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        Command::State { command } => match command {
+            StateCommand::Convert { to, input, output } => {
+                let input = input.unwrap_or_else(|| config.persist.path.clone());
+                let output = output.unwrap_or_else(|| input.clone());
+                let state = persist::decode(&std::fs::read(&input)?)?;
+                persist::save_state(&output, &persist::encode(&state, to)?)?;
+                Ok(())
+            }
+            StateCommand::Show { module } => state_request(StateRequest::Show { module }, config),
+            StateCommand::Clear { module } => state_request(StateRequest::Clear { module }, config),
+            StateCommand::Set { module, pairs } => {
+                let data: HashMap<String, String> = pairs.into_iter().collect();
+                state_request(StateRequest::Set { module, data }, config)
+            }
+        },
+    }
+}
+
+fn state_request(
+    request: StateRequest,
+    config: &Config,
+) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+    let socket = control::socket_path(&config.persist.path);
+    let response = match ask_running(&socket, &request) {
+        Some(response) => response?,
+        None => edit_file(request, config)?,
+    };
+    print_state(&response?);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use crate::ipc;
+use crate::process;
+use crate::types::{Button, BuiltinAction, ClickAction, ClickEvent, Control};
+use std::collections::HashMap;
+use std::error::Error;
+use std::result::Result as StdResult;
+use tokio::sync::mpsc::Sender;
+
+fn button_from_name(name: &str) -> Option<Button> {
+    match name {
+        "left" => Some(Button::Left),
+        "middle" => Some(Button::Middle),
+        "right" => Some(Button::Right),
+        "scroll_up" => Some(Button::ScrollUp),
+        "scroll_down" => Some(Button::ScrollDown),
+        "scroll_left" => Some(Button::ScrollLeft),
+        "scroll_right" => Some(Button::ScrollRight),
+        "back" => Some(Button::Back),
+        "forward" => Some(Button::Forward),
+        "double_click" => Some(Button::DoubleClick),
+        _ => None,
+    }
+}
+
+// Swaybar reports xkb modifier names (Shift, Control, Mod1, ...), while
+// configs are nicer to write as ctrl/alt/super. Caps and num lock are
+// dropped so they never stop a binding from matching.
+fn normalize_modifier(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "shift" => Some("shift"),
+        "control" | "ctrl" => Some("ctrl"),
+        "mod1" | "alt" => Some("alt"),
+        "mod3" => Some("mod3"),
+        "mod4" | "super" => Some("super"),
+        "mod5" => Some("mod5"),
+        _ => None,
+    }
+}
+
+fn normalize_modifiers<'a>(names: impl Iterator<Item = &'a str>) -> Vec<&'static str> {
+    let mut mods: Vec<&'static str> = names.filter_map(normalize_modifier).collect();
+    mods.sort_unstable();
+    mods.dedup();
+    mods
+}
+
+/// Checks a binding key such as `left` or `ctrl+shift+scroll_up` against an
+/// event.
+fn binding_matches(key: &str, event: &ClickEvent) -> bool {
+    let mut parts: Vec<&str> = key.split('+').map(str::trim).collect();
+    let Some(button) = parts.pop().and_then(button_from_name) else {
+        return false;
+    };
+    button == event.button()
+        && normalize_modifiers(parts.into_iter())
+            == normalize_modifiers(event.modifiers.iter().map(String::as_str))
+}
+
+pub fn find_action<'a>(
+    on_click: &'a HashMap<String, ClickAction>,
+    event: &ClickEvent,
+) -> Option<&'a ClickAction> {
+    on_click
+        .iter()
+        .find(|(key, _)| binding_matches(key, event))
+        .map(|(_, action)| action)
+}
+
+/// Whether a left click has to wait and see if a second one follows, which
+/// is only worth it if a `double_click` binding with the same modifiers
+/// would take the pair.
+pub fn wants_double_click(on_click: &HashMap<String, ClickAction>, event: &ClickEvent) -> bool {
+    let double = ClickEvent {
+        double_click: true,
+        ..event.clone()
+    };
+    find_action(on_click, &double).is_some()
+}
+
+/// Whether two clicks hit the same block with the same button.
+pub fn same_target(a: &ClickEvent, b: &ClickEvent) -> bool {
+    a.name == b.name && a.instance == b.instance && a.button == b.button
+}
+
+pub async fn run_action(
+    action: ClickAction,
+    name: String,
+    control: Sender<Control>,
+) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+    match action {
+        ClickAction::Exec(cmd) => {
+            process::command("sh").arg("-c").arg(cmd).output().await?;
+        }
+        ClickAction::Sway(cmd) => ipc::run_command(&cmd).await?,
+        ClickAction::Action(BuiltinAction::Refresh) => {
+            control.send(Control::Refresh(name)).await?;
+        }
+        ClickAction::Action(BuiltinAction::ToggleIdleInhibit) => {
+            control.send(Control::ToggleIdleInhibit).await?;
+        }
+    }
+    Ok(())
+}
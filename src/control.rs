@@ -0,0 +1,151 @@
+use crate::persist::State;
+use crate::types::Control;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+/// A request from `swaybar state ...`, sent as one JSON line over the
+/// control socket of a running bar or applied to the state file directly.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum StateRequest {
+    Show { module: Option<String> },
+    Clear { module: String },
+    Set { module: String, data: HashMap<String, String> },
+}
+
+pub type StateResponse = Result<State, String>;
+
+pub fn socket_path(persist_path: &str) -> String {
+    format!("{}.sock", persist_path)
+}
+
+/// Applies a request to `state`, returning the modules it touched. `known`
+/// decides which module names `set` may create.
+pub fn apply(
+    request: StateRequest,
+    state: &mut State,
+    known: impl Fn(&str) -> bool,
+) -> StateResponse {
+    match request {
+        StateRequest::Show { module: None } => Ok(state.clone()),
+        StateRequest::Show { module: Some(name) } => state
+            .get(&name)
+            .map(|meta| HashMap::from([(name.clone(), meta.clone())]))
+            .ok_or_else(|| format!("no state for {}", name)),
+        StateRequest::Clear { module } => state
+            .remove(&module)
+            .map(|meta| HashMap::from([(module.clone(), meta)]))
+            .ok_or_else(|| format!("no state for {}", module)),
+        StateRequest::Set { module, data } => {
+            if !state.contains_key(&module) && !known(&module) {
+                return Err(format!("no module named {}", module));
+            }
+            let meta = state.entry(module.clone()).or_default();
+            meta.data.extend(data);
+            Ok(HashMap::from([(module, meta.clone())]))
+        }
+    }
+}
+
+/// Reads a command to the bar from a sway tick payload, as sent by
+/// `swaymsg -t send_tick swaybar:refresh:<module>` or
+/// `swaymsg -t send_tick swaybar:set:<module>:<key>=<value>`. Ticks meant for
+/// other programs are ignored.
+pub fn from_tick(payload: &str) -> Option<Control> {
+    let command = payload.strip_prefix("swaybar:")?;
+    let parsed = match command.split_once(':') {
+        Some(("refresh", module)) if !module.is_empty() => {
+            Some(Control::Refresh(module.to_string()))
+        }
+        Some(("set", rest)) => rest
+            .split_once(':')
+            .and_then(|(module, pair)| Some((module, pair.split_once('=')?)))
+            .map(|(module, (key, value))| {
+                let data = HashMap::from([(key.to_string(), value.to_string())]);
+                Control::Update(module.to_string(), data)
+            }),
+        _ => None,
+    };
+    if parsed.is_none() {
+        eprintln!("ignoring unknown tick command {}", payload);
+    }
+    parsed
+}
+
+/// Serves state requests on a unix socket next to the state file, handing
+/// each one to the main loop, which owns the state.
+pub fn listen(path: String, chan: Sender<Control>) -> std::io::Result<()> {
+    if Path::new(&path).exists() {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+
+    tokio::task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let chan = chan.clone();
+            tokio::task::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                let Ok(Some(line)) = lines.next_line().await else {
+                    return;
+                };
+                let response: StateResponse = match serde_json::from_str(&line) {
+                    Ok(request) => {
+                        let (reply, answer) = oneshot::channel();
+                        let _ = chan.send(Control::State(request, reply)).await;
+                        answer.await.unwrap_or_else(|_| Err(String::from("bar is shutting down")))
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Ok(mut out) = serde_json::to_vec(&response) {
+                    out.push(b'\n');
+                    let _ = writer.write_all(&out).await;
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_refresh_ticks() {
+        assert!(matches!(
+            from_tick("swaybar:refresh:volume"),
+            Some(Control::Refresh(module)) if module == "volume"
+        ));
+    }
+
+    #[test]
+    fn reads_set_ticks() {
+        // Only the first `=` splits, so values may contain more of them.
+        let Some(Control::Update(module, data)) = from_tick("swaybar:set:note:text=a=b") else {
+            panic!("expected an update");
+        };
+        assert_eq!(module, "note");
+        assert_eq!(data, HashMap::from([(String::from("text"), String::from("a=b"))]));
+    }
+
+    #[test]
+    fn ignores_other_ticks() {
+        for payload in [
+            "",
+            "something else",
+            "swaybar:",
+            "swaybar:refresh:",
+            "swaybar:set:note",
+            "swaybar:set:note:text",
+            "swaybar:restart:volume",
+        ] {
+            assert!(from_tick(payload).is_none(), "{}", payload);
+        }
+    }
+}
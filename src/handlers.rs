@@ -4,88 +4,182 @@ use std::future::Future;
 use std::pin::Pin;
 use std::result::Result as StdResult;
 use std::collections::HashMap;
+use crate::types::{Block, ClickEvent, Control};
+use tokio::sync::mpsc::Sender;
 
 
-pub type BoxedHandler = fn() -> Pin<
+pub type BoxedHandler = fn(HashMap<String, String>) -> Pin<
     Box<
         dyn Future<Output = StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>>>
             + Send,
     >,
 >;
 
-pub type MouseBoxedHandler =
-    fn() -> Pin<Box<dyn Future<Output = StdResult<(), Box<dyn Error + Send + Sync>>> + Send>>;
+pub type MouseBoxedHandler = fn(
+    ClickEvent,
+    HashMap<String, String>,
+    HashMap<String, String>,
+    Sender<Control>,
+) -> Pin<Box<dyn Future<Output = StdResult<(), Box<dyn Error + Send + Sync>>> + Send>>;
 
 pub type RenderFn = fn(&HashMap<String, String>) -> String;
+/// Renders a module's data as any number of blocks, given its settings.
+pub type BlocksFn = fn(&HashMap<String, String>, &HashMap<String, String>) -> Vec<Block>;
 
+/// Turns a sway event into new data for a module, if it concerns it.
+pub type EventFn = fn(&swayipc::Event) -> Option<HashMap<String, String>>;
 
-pub mod volume_click {
+pub enum Render {
+    Text(RenderFn),
+    Blocks(BlocksFn),
+}
 
-    use std::error::Error;
-    use std::result::Result as StdResult;
-    use tokio::process::Command;
-    pub async fn click_handle() -> StdResult<(), Box<dyn Error + Send + Sync>> {
-        Command::new("pavucontrol").output().await?;
-        Ok(())
+impl Render {
+    pub fn blocks(
+        &self,
+        data: &HashMap<String, String>,
+        settings: &HashMap<String, String>,
+    ) -> Vec<Block> {
+        match self {
+            Render::Text(render) => vec![Block::text(render(data))],
+            Render::Blocks(render) => render(data, settings),
+        }
     }
 }
 
-pub mod prog_click {
+
+//pub struct MouseNoop;
+
+/// Flips a UI toggle stored in a module's data between two values and asks
+/// the main loop to store it, which re-renders and persists the module.
+pub async fn toggle_data(
+    name: String,
+    data: &HashMap<String, String>,
+    key: &str,
+    (a, b): (&str, &str),
+    control: &Sender<Control>,
+) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+    let next = if data.get(key).map(String::as_str) == Some(b) { a } else { b };
+    let update = HashMap::from([(key.to_string(), next.to_string())]);
+    control.send(Control::Update(name, update)).await?;
+    Ok(())
+}
+
+pub mod mouse_noop {
+    use crate::types::{ClickEvent, Control};
+    use std::collections::HashMap;
     use std::error::Error;
     use std::result::Result as StdResult;
-    use tokio::process::Command;
-    pub async fn click_handle() -> StdResult<(), Box<dyn Error + Send + Sync>> { 
-        Command::new("foot")
-            .arg("sh")
-            .arg("-c")
-            .arg("/home/tombert/.config/sway/prog-select")
-            .output()
-            .await?;
+    use tokio::sync::mpsc::Sender;
+    pub async fn click_handle(
+        _event: ClickEvent,
+        _settings: HashMap<String, String>,
+        _data: HashMap<String, String>,
+        _control: Sender<Control>,
+    ) -> StdResult<(), Box<dyn Error + Send + Sync>> {
         Ok(())
-
     }
-    
-
 }
 
-
-
-pub mod wifi_click {
-
+pub mod volume_click {
+    use crate::process;
+    use crate::types::{Button, ClickEvent, Control};
+    use std::collections::HashMap;
     use std::error::Error;
     use std::result::Result as StdResult;
-    use tokio::process::Command;
-    pub async fn click_handle() -> StdResult<(), Box<dyn Error + Send + Sync>> {
-        Command::new("pkill").arg("iwgtk").output().await?;
-        Command::new("iwgtk").output().await?;
+    use tokio::sync::mpsc::Sender;
 
+    fn setting(settings: &HashMap<String, String>, key: &str, default: i32) -> i32 {
+        settings
+            .get(key)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// Takes anything `pactl set-sink-volume` does: `50%`, or a relative
+    /// `+5%` or `-5%`.
+    async fn set_volume(volume: &str) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        process::command("pactl")
+            .arg("set-sink-volume")
+            .arg("@DEFAULT_SINK@")
+            .arg(volume)
+            .output()
+            .await?;
         Ok(())
     }
-}
 
-//pub struct MouseNoop;
+    async fn current_volume() -> StdResult<i32, Box<dyn Error + Send + Sync>> {
+        let level = super::volume::handle(HashMap::new())
+            .await?
+            .get("volume_level")
+            .and_then(|v| v.parse().ok())
+            .ok_or("could not read the current volume")?;
+        Ok(level)
+    }
+
+    pub async fn click_handle(
+        event: ClickEvent,
+        settings: HashMap<String, String>,
+        _data: HashMap<String, String>,
+        control: Sender<Control>,
+    ) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        let step = setting(&settings, "step", 5);
+        let max = setting(&settings, "max", 100);
+
+        match event.button() {
+            // Steps are relative so that scroll notches handled at the same
+            // time all count, rather than each setting the level it read.
+            Button::ScrollUp => {
+                // Don't pull the volume down if something else already
+                // pushed it past the cap.
+                if current_volume().await? < max {
+                    set_volume(&format!("+{}%", step)).await?;
+                    if current_volume().await? > max {
+                        set_volume(&format!("{}%", max)).await?;
+                    }
+                }
+            }
+            Button::ScrollDown => set_volume(&format!("-{}%", step)).await?,
+            Button::Middle => {
+                process::command("pactl")
+                    .arg("set-sink-mute")
+                    .arg("@DEFAULT_SINK@")
+                    .arg("toggle")
+                    .output()
+                    .await?;
+            }
+            _ => return Ok(()),
+        }
 
-pub mod mouse_noop {
-    use std::error::Error;
-    use std::result::Result as StdResult;
-    pub async fn click_handle() -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        control.send(Control::Refresh(event.name)).await?;
         Ok(())
     }
 }
 
 pub mod bg_changer {
+    use crate::ipc;
     use rand::Rng;
     use rand::SeedableRng;
     use rand::rngs::StdRng;
     use std::collections::HashMap;
     use std::error::Error;
+    use std::path::Path;
     use std::result::Result as StdResult;
     use tokio::fs;
-    use tokio::process::Command;
     use tokio_stream::StreamExt;
     use tokio_stream::wrappers::ReadDirStream;
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-        let mut entries = ReadDirStream::new(fs::read_dir("/home/tombert/wallpapers/").await?);
+
+    fn setting<'a>(settings: &'a HashMap<String, String>, key: &str, default: &'a str) -> &'a str {
+        settings.get(key).map(String::as_str).unwrap_or(default)
+    }
+
+    /// Sets a random image from `dir` as the background of `output` through
+    /// sway's own `output bg`, so no swaybg has to be managed here.
+    pub async fn handle(
+        settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let dir = setting(&settings, "dir", "/home/tombert/wallpapers");
+        let mut entries = ReadDirStream::new(fs::read_dir(dir).await?);
         let mut files = Vec::new();
 
         while let Some(entry) = entries.next().await {
@@ -99,25 +193,22 @@ pub mod bg_changer {
                 files.push(file_name);
             }
         }
+        if files.is_empty() {
+            return Err(format!("no images in {}", dir).into());
+        }
 
         let mut rng = StdRng::from_entropy();
         let random_num = rng.gen_range(0..files.len());
-        let image = files[random_num].to_string_lossy().to_string();
-        let image = format!("/home/tombert/wallpapers/{}", image);
-        Command::new("pkill").arg("swaybg").output().await?;
-        tokio::spawn(async {
-            let _ = Command::new("swaybg")
-                .arg("-i")
-                .arg(image)
-                .arg("-m")
-                .arg("stretch")
-                .output()
-                .await;
-        });
-
-        let mut out_hash = HashMap::new();
-        out_hash.insert(String::from(""), String::from(""));
-        Ok(out_hash)
+        let image = Path::new(dir).join(&files[random_num]).to_string_lossy().to_string();
+        ipc::run_command(&format!(
+            "output {} bg {} {}",
+            setting(&settings, "output", "*"),
+            ipc::quote(&image),
+            setting(&settings, "mode", "stretch"),
+        ))
+        .await?;
+
+        Ok(HashMap::from([(String::from("image"), image)]))
     }
     pub fn render(_i: &HashMap<String, String>) -> String {
         String::from("")
@@ -130,7 +221,9 @@ pub mod noop {
     use std::error::Error;
     use std::result::Result as StdResult;
 
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
         let out_hash = HashMap::from([(String::from(""), String::from(""))]);
         Ok(out_hash)
     }
@@ -141,11 +234,36 @@ pub mod noop {
 
 
 
+pub mod idle {
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::result::Result as StdResult;
+
+    /// All of the data comes from the idle listener; running the module adds
+    /// nothing to it.
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        Ok(HashMap::new())
+    }
+
+    /// The data is pushed by the idle listener, so there is nothing to render
+    /// until it has reported once.
+    pub fn render(i: &HashMap<String, String>) -> String {
+        match i.get("reason").map(String::as_str) {
+            Some("") => String::from("idle"),
+            Some(reason) => format!("inhibited: {}", reason),
+            None => String::from("nada"),
+        }
+    }
+}
+
 pub mod current_program {
     use std::collections::HashMap;
     use std::error::Error;
     use std::result::Result as StdResult;
-    use swayipc::{Connection, Node};
+    use crate::ipc;
+    use swayipc::{Event, Floating, Node, NodeType, WindowChange, WorkspaceChange};
 
     fn find_focused(node: &Node) -> Option<&Node> {
         if node.focused {
@@ -164,36 +282,298 @@ pub mod current_program {
         None
     }
 
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-        let mut connection = Connection::new().expect("Failed to connect to sway");
-        let tree = connection.get_tree().expect("Failed to get tree");
-
-        let om = if let Some(focused) = find_focused(&tree) {
-            let app_id = focused.app_id.as_deref();
-            let class = focused
-                .window_properties
+    fn focused_data(node: &Node) -> HashMap<String, String> {
+        let app_id = node.app_id.as_deref();
+        let class = node
+            .window_properties
+            .as_ref()
+            .and_then(|props| props.class.as_deref());
+        let floating = node.node_type == NodeType::FloatingCon
+            || matches!(node.floating, Some(Floating::AutoOn | Floating::UserOn));
+        let fullscreen = node.fullscreen_mode.is_some_and(|mode| mode > 0);
+        HashMap::from([
+            (String::from("out"), String::from(app_id.or(class).unwrap_or("unknown"))),
+            (String::from("title"), node.name.clone().unwrap_or_default()),
+            (String::from("app_id"), app_id.unwrap_or("").to_string()),
+            (String::from("class"), class.unwrap_or("").to_string()),
+            (String::from("floating"), floating.to_string()),
+            (String::from("fullscreen"), fullscreen.to_string()),
+        ])
+    }
+
+    fn nothing_focused() -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("out"), String::from("nothing")),
+            (String::from("title"), String::new()),
+            (String::from("app_id"), String::new()),
+            (String::from("class"), String::new()),
+            (String::from("floating"), String::from("false")),
+            (String::from("fullscreen"), String::from("false")),
+        ])
+    }
+
+    /// The new data for the module after a sway event, if the event changed
+    /// what is focused or what the focused window looks like.
+    pub fn from_event(event: &Event) -> Option<HashMap<String, String>> {
+        match event {
+            Event::Window(window) if window.container.focused => match window.change {
+                WindowChange::Focus
+                | WindowChange::Title
+                | WindowChange::FullscreenMode
+                | WindowChange::Floating => Some(focused_data(&window.container)),
+                WindowChange::Close => Some(nothing_focused()),
+                _ => None,
+            },
+            // Switching to an empty workspace focuses no window at all, so
+            // there is no window event for it.
+            Event::Workspace(workspace) if workspace.change == WorkspaceChange::Focus => workspace
+                .current
                 .as_ref()
-                .and_then(|props| props.class.as_deref());
-            let name = app_id.or(class).unwrap_or("unknown");
-            HashMap::from([(String::from("out"), String::from(name))])
-        } else {
-            HashMap::from([(String::from("out"), String::from("nothing"))])
-        };
+                .filter(|ws| ws.nodes.is_empty() && ws.floating_nodes.is_empty())
+                .map(|_| nothing_focused()),
+            _ => None,
+        }
+    }
 
-        Ok(om)
+    /// Only fetches the initial focus; after that the module is kept up to
+    /// date from window events.
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let tree: Node = serde_json::from_slice(&ipc::request(ipc::GET_TREE, "").await?)?;
+        Ok(find_focused(&tree).map(focused_data).unwrap_or_else(nothing_focused))
     }
     pub fn render(i: &HashMap<String, String>) -> String {
-        format!(
-            "{}",
-            i.get(&String::from("out")).unwrap_or(&String::from("nada"))
-        )
+        if i.get("available").is_some_and(|a| a == "false") {
+            return String::from("unavailable");
+        }
+        i.get("out").cloned().unwrap_or_else(|| String::from("nada"))
+    }
+}
+
+pub mod workspaces {
+    use crate::ipc;
+    use crate::types::{Block, Button, ClickEvent, Control};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::result::Result as StdResult;
+    use swayipc::Workspace;
+    use tokio::sync::mpsc::Sender;
+
+    #[derive(Serialize, Deserialize)]
+    struct Entry {
+        name: String,
+        focused: bool,
+        visible: bool,
+        urgent: bool,
+    }
+
+    /// Fetches the workspace list. The module refreshes on every workspace
+    /// event, so this only runs when something changed.
+    pub async fn handle(
+        settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let reply = ipc::request(ipc::GET_WORKSPACES, "").await?;
+        let workspaces: Vec<Workspace> = serde_json::from_slice(&reply)?;
+        let entries: Vec<Entry> = workspaces
+            .into_iter()
+            .filter(|ws| settings.get("output").is_none_or(|output| *output == ws.output))
+            .map(|ws| Entry {
+                name: ws.name,
+                focused: ws.focused,
+                visible: ws.visible,
+                urgent: ws.urgent,
+            })
+            .collect();
+        Ok(HashMap::from([(
+            String::from("workspaces"),
+            serde_json::to_string(&entries)?,
+        )]))
+    }
+
+    /// Swaybar's own default colors as (text, background, border).
+    fn default_colors(style: &str) -> (&'static str, &'static str, &'static str) {
+        match style {
+            "focused" => ("#ffffff", "#285577", "#4c7899"),
+            "visible" => ("#ffffff", "#5f676a", "#333333"),
+            "urgent" => ("#ffffff", "#900000", "#2f343a"),
+            _ => ("#888888", "#222222", "#333333"),
+        }
+    }
+
+    fn color(settings: &HashMap<String, String>, style: &str, part: &str, default: &str) -> String {
+        settings
+            .get(&format!("{}_{}", style, part))
+            .cloned()
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    pub fn render(data: &HashMap<String, String>, settings: &HashMap<String, String>) -> Vec<Block> {
+        if data.get("available").is_some_and(|a| a == "false") {
+            return vec![Block::text(String::from("unavailable"))];
+        }
+        let entries: Vec<Entry> = data
+            .get("workspaces")
+            .and_then(|w| serde_json::from_str(w).ok())
+            .unwrap_or_default();
+        entries
+            .into_iter()
+            .map(|ws| {
+                let style = if ws.urgent {
+                    "urgent"
+                } else if ws.focused {
+                    "focused"
+                } else if ws.visible {
+                    "visible"
+                } else {
+                    "inactive"
+                };
+                let (text, background, border) = default_colors(style);
+                Block {
+                    full_text: ws.name.clone(),
+                    instance: Some(ws.name),
+                    color: Some(color(settings, style, "text", text)),
+                    background: Some(color(settings, style, "background", background)),
+                    border: Some(color(settings, style, "border", border)),
+                    urgent: ws.urgent,
+                }
+            })
+            .collect()
+    }
+
+    /// Clicking a workspace switches to it; scrolling cycles through the
+    /// workspaces on the focused output.
+    pub async fn click_handle(
+        event: ClickEvent,
+        _settings: HashMap<String, String>,
+        _data: HashMap<String, String>,
+        _control: Sender<Control>,
+    ) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        let command = match event.button() {
+            Button::Left => format!("workspace {}", ipc::quote(&event.instance)),
+            Button::ScrollUp => String::from("workspace prev_on_output"),
+            Button::ScrollDown => String::from("workspace next_on_output"),
+            _ => return Ok(()),
+        };
+        ipc::run_command(&command).await
+    }
+}
+
+pub mod mode {
+    use crate::ipc;
+    use crate::types::Block;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::result::Result as StdResult;
+    use swayipc::{BindingState, Event};
+
+    fn mode_data(mode: String) -> HashMap<String, String> {
+        HashMap::from([(String::from("mode"), mode)])
+    }
+
+    /// Only fetches the mode at startup; after that it follows mode events.
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let reply = ipc::request(ipc::GET_BINDING_STATE, "").await?;
+        let state: BindingState = serde_json::from_slice(&reply)?;
+        Ok(mode_data(state.name))
+    }
+
+    pub fn from_event(event: &Event) -> Option<HashMap<String, String>> {
+        match event {
+            Event::Mode(mode) => Some(mode_data(mode.change.clone())),
+            _ => None,
+        }
+    }
+
+    /// Hidden in the default mode. `label.<mode>` replaces a mode's name and
+    /// `urgent` set to `false` turns off the urgent styling.
+    pub fn render(data: &HashMap<String, String>, settings: &HashMap<String, String>) -> Vec<Block> {
+        if data.get("available").is_some_and(|a| a == "false") {
+            return vec![Block::text(String::from("unavailable"))];
+        }
+        let mode = match data.get("mode") {
+            Some(mode) if mode != "default" => mode,
+            _ => return Vec::new(),
+        };
+        let label = settings.get(&format!("label.{}", mode)).unwrap_or(mode);
+        vec![Block {
+            full_text: label.clone(),
+            urgent: settings.get("urgent").is_none_or(|u| u != "false"),
+            ..Block::default()
+        }]
+    }
+}
+
+pub mod keyboard_layout {
+    use crate::ipc;
+    use crate::types::{Block, Button, ClickEvent, Control};
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::result::Result as StdResult;
+    use swayipc::{Event, Input};
+    use tokio::sync::mpsc::Sender;
+
+    fn layout_data(input: &Input) -> Option<HashMap<String, String>> {
+        if input.input_type != "keyboard" {
+            return None;
+        }
+        let layout = input.xkb_active_layout_name.clone()?;
+        Some(HashMap::from([(String::from("layout"), layout)]))
+    }
+
+    /// Only fetches the layout at startup; after that it follows input events.
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let reply = ipc::request(ipc::GET_INPUTS, "").await?;
+        let inputs: Vec<Input> = serde_json::from_slice(&reply)?;
+        inputs
+            .iter()
+            .find_map(layout_data)
+            .ok_or_else(|| "no keyboard with an xkb layout".into())
+    }
+
+    pub fn from_event(event: &Event) -> Option<HashMap<String, String>> {
+        match event {
+            Event::Input(event) => layout_data(&event.input),
+            _ => None,
+        }
+    }
+
+    /// `label.<layout>` replaces a layout's name, e.g. `label.English (US)`
+    /// set to `US`.
+    pub fn render(data: &HashMap<String, String>, settings: &HashMap<String, String>) -> Vec<Block> {
+        if data.get("available").is_some_and(|a| a == "false") {
+            return vec![Block::text(String::from("unavailable"))];
+        }
+        let text = match data.get("layout") {
+            Some(layout) => settings
+                .get(&format!("label.{}", layout))
+                .unwrap_or(layout)
+                .clone(),
+            None => String::from("nada"),
+        };
+        vec![Block::text(text)]
+    }
+
+    pub async fn click_handle(
+        event: ClickEvent,
+        _settings: HashMap<String, String>,
+        _data: HashMap<String, String>,
+        _control: Sender<Control>,
+    ) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        if event.button() != Button::Left {
+            return Ok(());
+        }
+        ipc::run_command("input type:keyboard xkb_switch_layout next").await
     }
 }
 
 pub mod quote {
     use rand::Rng;
-    use rand::SeedableRng;
-    use rand::rngs::StdRng;
     use std::fs::File;
     use std::io::{BufRead, BufReader};
     use reqwest::Client;
@@ -251,7 +631,7 @@ pub mod quote {
             .await?;
 
         let data: ChatResponse = res.json().await?;
-        let quote = &data.choices.get(0).unwrap().message.content;
+        let quote = &data.choices.first().unwrap().message.content;
 
         Ok(quote.trim().to_string())
     }
@@ -271,7 +651,9 @@ pub mod quote {
         selected
     }
 
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
         //let topics_str = tokio::fs::read_to_string().await?;
         let topic = pick_random_line("/home/tombert/.config/sway/topics").unwrap();
         //let topics: Vec<String> = topics_str.lines().map(|i| i.to_string()).collect();
@@ -290,7 +672,7 @@ pub mod quote {
     pub fn render(i: &HashMap<String, String>) -> String {
         let error_text = String::from("ERROR!");
         let quote = i.get(&String::from("quote")).unwrap_or(&error_text);
-        format!("{}", quote)
+        quote.to_string()
     }
 }
 
@@ -298,9 +680,11 @@ pub mod quote {
 
 pub mod battery {
 
+    use crate::types::{Button, ClickEvent, Control};
     use std::collections::HashMap;
     use std::error::Error;
     use std::result::Result as StdResult;
+    use tokio::sync::mpsc::Sender;
 
     fn bat_status_icons(n: &str) -> &'static str {
         match n {
@@ -312,7 +696,36 @@ pub mod battery {
         }
     }
 
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    fn read_number(path: String) -> Option<f64> {
+        std::fs::read_to_string(path).ok()?.trim().parse().ok()
+    }
+
+    // Batteries report either energy (µWh, µW) or charge (µAh, µA); both
+    // work out to hours when divided.
+    fn remaining(bat_path: &str, status: &str) -> Option<String> {
+        let (now, full, rate) = ["energy", "charge"].iter().find_map(|kind| {
+            let rate_name = if *kind == "energy" { "power" } else { "current" };
+            Some((
+                read_number(format!("{}/{}_now", bat_path, kind))?,
+                read_number(format!("{}/{}_full", bat_path, kind))?,
+                read_number(format!("{}/{}_now", bat_path, rate_name))?,
+            ))
+        })?;
+        if rate <= 0.0 {
+            return None;
+        }
+        let hours = match status {
+            "discharging" => now / rate,
+            "charging" => (full - now).max(0.0) / rate,
+            _ => return None,
+        };
+        let minutes = (hours * 60.0).round() as u64;
+        Some(format!("{}:{:02}", minutes / 60, minutes % 60))
+    }
+
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
         let bat_path = "/sys/class/power_supply/BAT0";
         let cap_path = format!("{}/capacity", bat_path);
         let stat_path = format!("{}/status", bat_path);
@@ -321,27 +734,46 @@ pub mod battery {
             .trim()
             .to_lowercase()
             .replace(" ", "");
+        let remaining = remaining(bat_path, &stat_string).unwrap_or_default();
 
         let out_map = HashMap::from(
             [(String::from("capacity"), cap_string.to_string()),
-            (String::from("status"), stat_string.to_string()) ]);
+            (String::from("status"), stat_string.to_string()),
+            (String::from("remaining"), remaining)]);
 
         Ok(out_map)
     }
 
+    pub async fn click_handle(
+        event: ClickEvent,
+        _settings: HashMap<String, String>,
+        data: HashMap<String, String>,
+        control: Sender<Control>,
+    ) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        if event.button() == Button::Left {
+            super::toggle_data(event.name, &data, "format", ("percent", "time"), &control).await?;
+        }
+        Ok(())
+    }
+
     pub fn render(i: &HashMap<String, String>) -> String {
         let empty = String::from("");
         let cap = i.get("capacity").unwrap_or(&empty);
         let stat = i.get("status").unwrap_or(&empty).as_str();
-        format!("{} {}%", bat_status_icons(stat), cap)
+        let remaining = i.get("remaining").unwrap_or(&empty);
+        if i.get("format").map(String::as_str) == Some("time") && !remaining.is_empty() {
+            format!("{} {}", bat_status_icons(stat), remaining)
+        } else {
+            format!("{} {}%", bat_status_icons(stat), cap)
+        }
     }
 }
 
 pub mod wifi {
+    use crate::process;
     use std::collections::HashMap;
     use std::error::Error;
     use std::result::Result as StdResult;
-    use tokio::process::Command;
 
     fn wifi_status_icons(n: &str) -> &'static str {
         match n {
@@ -351,25 +783,24 @@ pub mod wifi {
         }
     }
 
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-        let wifi_cmd = Command::new("iw").arg("dev").output().await?;
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let wifi_cmd = process::command("iw").arg("dev").output().await?;
         let s: Vec<String> = String::from_utf8_lossy(&wifi_cmd.stdout)
             .lines()
             .map(|s| s.trim().to_string())
             .collect();
         let interface: &str = s[5].split(" ").last().unwrap_or("");
 
-        let connected_cmd = Command::new("iw")
+        let connected_cmd = process::command("iw")
             .arg(interface)
             .arg("link")
             .output()
             .await?;
         let s2 = String::from_utf8_lossy(&connected_cmd.stdout).find("Connected");
 
-        let is_connected = match s2 {
-            Some(_) => true,
-            None => false,
-        };
+        let is_connected = s2.is_some();
 
         let connect_status = if is_connected {
             "connected"
@@ -377,7 +808,15 @@ pub mod wifi {
             "disconnected"
         };
 
-        let out_map = HashMap::from([(String::from("connect_status"), String::from(connect_status))]);
+        let stats_path = format!("/sys/class/net/{}/statistics", interface);
+        let rx_bytes = std::fs::read_to_string(format!("{}/rx_bytes", stats_path)).unwrap_or_default();
+        let tx_bytes = std::fs::read_to_string(format!("{}/tx_bytes", stats_path)).unwrap_or_default();
+
+        let out_map = HashMap::from([
+            (String::from("connect_status"), String::from(connect_status)),
+            (String::from("rx_bytes"), rx_bytes.trim().to_string()),
+            (String::from("tx_bytes"), tx_bytes.trim().to_string()),
+        ]);
 
         Ok(out_map)
     }
@@ -385,7 +824,7 @@ pub mod wifi {
     pub fn render(i: &HashMap<String, String>) -> String {
         let empty = String::from("");
         let connected = i.get("connect_status").unwrap_or(&empty);
-        format!("{}", wifi_status_icons(&connected))
+        wifi_status_icons(connected).to_string()
     }
 }
 
@@ -394,10 +833,10 @@ pub mod wifi {
 
 pub mod volume {
 
+    use crate::process;
     use std::collections::HashMap;
     use std::error::Error;
     use std::result::Result as StdResult;
-    use tokio::process::Command;
 
     fn get_volume_icon(vol_level: i32, is_muted: bool) -> &'static str {
         let small_speaker_cutoff = 40;
@@ -413,14 +852,16 @@ pub mod volume {
         }
     }
 
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    pub async fn handle(
+        _settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
         let space = String::from(" ");
-        let is_muted_cmd = Command::new("pactl")
+        let is_muted_cmd = process::command("pactl")
             .arg("get-sink-mute")
             .arg("@DEFAULT_SINK@")
             .output()
             .await?;
-        let vol_info_cmd = Command::new("pactl")
+        let vol_info_cmd = process::command("pactl")
             .arg("get-sink-volume")
             .arg("@DEFAULT_SINK@")
             .output()
@@ -445,8 +886,8 @@ pub mod volume {
         .replace("%", "");
         let out_map =
             HashMap::from(
-                [(String::from("volume_level"), String::from(vol_level)), 
-                (String::from("is_muted"), String::from(is_muted))]);
+                [(String::from("volume_level"), vol_level),
+                (String::from("is_muted"), is_muted)]);
 
         Ok(out_map)
     }
@@ -468,68 +909,82 @@ pub mod volume {
 
 
 pub mod date {
-    use chrono::{Datelike, Local, Timelike};
+    use crate::types::{Button, ClickEvent, Control};
+    use chrono::{Local, Locale, Utc};
+    use chrono_tz::Tz;
     use std::collections::HashMap;
     use std::error::Error;
+    use std::fmt::Write;
     use std::result::Result as StdResult;
-
-    fn month_abbr(n: u32) -> &'static str {
-        match n {
-            1 => "Jan",
-            2 => "Feb",
-            3 => "Mar",
-            4 => "Apr",
-            5 => "May",
-            6 => "Jun",
-            7 => "Jul",
-            8 => "Aug",
-            9 => "Sep",
-            10 => "Oct",
-            11 => "Nov",
-            12 => "Dec",
-            _ => "???",
-        }
-    }
-
-    pub async fn handle() -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-        let now = Local::now();
-        let weekday = now.weekday();
-        let day = now.day();
-        let month = month_abbr(now.month());
-        let seconds = format!("{:02}", now.second());
-        let mod_hour = if now.hour() % 12 == 0 {
-            12
-        } else {
-            now.hour() % 12
+    use tokio::sync::mpsc::Sender;
+
+    const FORMAT_12H: &str = "%a %b %-d %I:%M %S %p";
+    const FORMAT_24H: &str = "%a %b %-d %H:%M %S";
+    const LONG_FORMAT_12H: &str = "%A, %B %-d %Y %I:%M:%S %p";
+    const LONG_FORMAT_24H: &str = "%A, %B %-d %Y %H:%M:%S";
+
+    pub async fn handle(
+        settings: HashMap<String, String>,
+    ) -> StdResult<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+        let clock = settings.get("clock").map(String::as_str);
+        let (default_format, default_long_format) = match clock {
+            Some("24") => (FORMAT_24H, LONG_FORMAT_24H),
+            _ => (FORMAT_12H, LONG_FORMAT_12H),
+        };
+        let format = settings.get("format").map(String::as_str).unwrap_or(default_format);
+        let long_format = settings
+            .get("long_format")
+            .map(String::as_str)
+            .unwrap_or(default_long_format);
+        let locale = match settings.get("locale") {
+            Some(name) => Locale::try_from(name.as_str())
+                .map_err(|_| format!("unknown locale {}", name))?,
+            None => Locale::POSIX,
         };
 
-        let hour = format!("{:02}", mod_hour);
-        let minutes = format!("{:02}", now.minute());
+        // Writing through fmt::Write surfaces a bad strftime string as an
+        // error instead of the panic `to_string` would give us.
+        let mut time = String::new();
+        let mut long_time = String::new();
+        match settings.get("timezone") {
+            Some(name) => {
+                let tz: Tz = name
+                    .parse()
+                    .map_err(|_| format!("unknown timezone {}", name))?;
+                let now = Utc::now().with_timezone(&tz);
+                write!(time, "{}", now.format_localized(format, locale))?;
+                write!(long_time, "{}", now.format_localized(long_format, locale))?;
+            }
+            None => {
+                let now = Local::now();
+                write!(time, "{}", now.format_localized(format, locale))?;
+                write!(long_time, "{}", now.format_localized(long_format, locale))?;
+            }
+        }
 
-        let out_hash = 
-            HashMap::from(
-                [(String::from("weekday"), weekday.to_string()),
-                (String::from("day"), day.to_string()),
-                (String::from("month"), month.to_string()),
-                (String::from("seconds"), seconds.to_string()),
-                (String::from("minutes"), minutes.to_string()),
-                (String::from("hour"), hour.to_string())]);
+        Ok(HashMap::from([
+            (String::from("time"), time),
+            (String::from("long_time"), long_time),
+        ]))
+    }
 
-        Ok(out_hash)
+    pub async fn click_handle(
+        event: ClickEvent,
+        _settings: HashMap<String, String>,
+        data: HashMap<String, String>,
+        control: Sender<Control>,
+    ) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+        if event.button() == Button::Left {
+            super::toggle_data(event.name, &data, "format", ("short", "long"), &control).await?;
+        }
+        Ok(())
     }
 
     pub fn render(i: &HashMap<String, String>) -> String {
-        static EMPTY: String = String::new();
-
-        let hour = i.get("hour").unwrap_or(&EMPTY);
-        let minute = i.get("minutes").unwrap_or(&EMPTY);
-        let day = i.get("day").unwrap_or(&EMPTY);
-        let month = i.get("month").unwrap_or(&EMPTY);
-        let weekday = i.get("weekday").unwrap_or(&EMPTY);
-        let seconds = i.get("seconds").unwrap_or(&EMPTY);
-        format!(
-            "{} {} {} {}:{} {}",
-            weekday, month, day, hour, minute, seconds
-        )
+        let key = match i.get("format").map(String::as_str) {
+            Some("long") => "long_time",
+            _ => "time",
+        };
+        i.get(key).cloned().unwrap_or_default()
     }
 }
@@ -0,0 +1,95 @@
+use crate::types::{Config, HistoryConfig};
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+
+const DEFAULT_KEEP: usize = 3;
+
+/// The time the first row of an existing history file was written, so age
+/// based rotation survives restarts.
+fn first_timestamp(path: &str) -> Option<DateTime<Local>> {
+    let mut reader = csv::Reader::from_path(path).ok()?;
+    let record = reader.records().next()?.ok()?;
+    let parsed = DateTime::parse_from_rfc3339(record.get(0)?).ok()?;
+    Some(parsed.with_timezone(&Local))
+}
+
+fn rotate(history: &HistoryConfig) -> std::io::Result<()> {
+    let keep = history.keep.unwrap_or(DEFAULT_KEEP);
+    if keep == 0 {
+        return fs::remove_file(&history.path);
+    }
+    let _ = fs::remove_file(format!("{}.{}", history.path, keep));
+    for i in (1..keep).rev() {
+        let from = format!("{}.{}", history.path, i);
+        if Path::new(&from).exists() {
+            fs::rename(&from, format!("{}.{}", history.path, i + 1))?;
+        }
+    }
+    fs::rename(&history.path, format!("{}.1", history.path))
+}
+
+fn needs_rotation(history: &HistoryConfig, started: DateTime<Local>, now: DateTime<Local>) -> bool {
+    let size = fs::metadata(&history.path).map(|m| m.len()).unwrap_or(0);
+    let too_big = history.max_bytes.is_some_and(|max| size >= max);
+    let too_old = history.max_age.is_some_and(|max| {
+        let age = now.signed_duration_since(started).to_std().unwrap_or(Duration::ZERO);
+        age >= Duration::from_millis(max)
+    });
+    size > 0 && (too_big || too_old)
+}
+
+fn append(
+    history: &HistoryConfig,
+    data: &HashMap<String, String>,
+    now: DateTime<Local>,
+) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+    let is_new = fs::metadata(&history.path).map(|m| m.len() == 0).unwrap_or(true);
+    let file = OpenOptions::new().create(true).append(true).open(&history.path)?;
+    let mut writer = csv::Writer::from_writer(file);
+    if is_new {
+        let header = history.keys.iter().map(String::as_str);
+        writer.write_record(std::iter::once("timestamp").chain(header))?;
+    }
+    let row = history
+        .keys
+        .iter()
+        .map(|key| data.get(key).map(String::as_str).unwrap_or(""));
+    writer.write_record(std::iter::once(now.to_rfc3339().as_str()).chain(row))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Appends a row for every module update it receives to that module's
+/// history file, rotating the file once it grows too big or too old.
+pub fn write_history(mut chan: Receiver<(String, HashMap<String, String>)>, config: Arc<Config>) {
+    tokio::task::spawn(async move {
+        let mut started = HashMap::<String, DateTime<Local>>::new();
+        while let Some((name, data)) = chan.recv().await {
+            let Some(history) = config
+                .modules
+                .iter()
+                .find(|m| m.name == name)
+                .and_then(|m| m.history.as_ref())
+            else {
+                continue;
+            };
+            let now = Local::now();
+            let file_start = *started
+                .entry(name.clone())
+                .or_insert_with(|| first_timestamp(&history.path).unwrap_or(now));
+            if needs_rotation(history, file_start, now) && rotate(history).is_ok() {
+                started.insert(name.clone(), now);
+            }
+            if let Err(e) = append(history, &data, now) {
+                eprintln!("could not write history for {}: {}", name, e);
+            }
+        }
+    });
+}
@@ -0,0 +1,180 @@
+use crate::handlers::current_program;
+use crate::process;
+use crate::types::{Config, Control, InhibitConfig};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+
+const DEFAULT_AUDIO_INTERVAL: u64 = 5000;
+
+fn run(command: &str) {
+    if let Err(e) = process::command("sh").arg("-c").arg(command).spawn() {
+        eprintln!("could not run {}: {}", command, e);
+    }
+}
+
+/// Whether any stream is playing. Paused streams stay listed as corked, so
+/// just having sink inputs isn't enough.
+async fn audio_playing() -> bool {
+    match process::command("pactl")
+        .args(["list", "sink-inputs"])
+        .env("LC_ALL", "C")
+        .output()
+        .await
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.trim() == "Corked: no"),
+        Err(_) => false,
+    }
+}
+
+/// What is currently known about the things that can keep the machine awake.
+#[derive(Default)]
+struct Inhibitors {
+    focused: HashMap<String, String>,
+    audio: bool,
+    manual: bool,
+}
+
+impl Inhibitors {
+    fn reason(&self, config: &InhibitConfig) -> Option<&'static str> {
+        let focused = |key: &str| self.focused.get(key).map(String::as_str).unwrap_or("");
+        if self.manual {
+            Some("manual")
+        } else if config.fullscreen.unwrap_or(true) && focused("fullscreen") == "true" {
+            Some("fullscreen")
+        } else if config
+            .app_ids
+            .iter()
+            .any(|id| id == focused("app_id") || id == focused("class"))
+        {
+            Some("app_id")
+        } else if config.audio.unwrap_or(true) && self.audio {
+            Some("audio")
+        } else {
+            None
+        }
+    }
+}
+
+async fn report(control: &Sender<Control>, modules: &[String], reason: Option<&str>) {
+    let data = HashMap::from([
+        (String::from("inhibited"), reason.is_some().to_string()),
+        (String::from("reason"), reason.unwrap_or("").to_string()),
+    ]);
+    for name in modules {
+        let _ = control.send(Control::Update(name.clone(), data.clone())).await;
+    }
+}
+
+/// Runs each stage's command once no sway event has come in for its timeout,
+/// in order of their timeouts. Any event counts as activity: it runs the
+/// resume commands of the stages that fired, newest first, and starts over.
+/// Input inside a window produces no event, so this can't tell someone typing
+/// away in one window from an empty desk.
+/// Nothing fires while an inhibitor is active, and the reason is pushed to
+/// every `idle` module.
+pub fn idle_listener(
+    mut events: broadcast::Receiver<swayipc::Event>,
+    mut manual: watch::Receiver<bool>,
+    config: Arc<Config>,
+    control: Sender<Control>,
+) {
+    let mut stages = config.idle_stages();
+    let inhibit = config.idle_inhibit();
+    let modules: Vec<String> = config
+        .modules
+        .iter()
+        .filter(|m| m.kind() == "idle")
+        .map(|m| m.name.clone())
+        .collect();
+    if stages.is_empty() && modules.is_empty() {
+        return;
+    }
+    stages.sort_by_key(|stage| stage.timeout);
+
+    tokio::task::spawn(async move {
+        let mut inhibitors = Inhibitors::default();
+        if let Ok(focused) = current_program::handle(HashMap::new()).await {
+            inhibitors.focused = focused;
+        }
+        let check_audio = inhibit.audio.unwrap_or(true);
+        let audio_interval = inhibit.audio_interval.unwrap_or(DEFAULT_AUDIO_INTERVAL);
+        let mut audio_timer = tokio::time::interval(Duration::from_millis(audio_interval));
+        audio_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut reason = inhibitors.reason(&inhibit);
+        report(&control, &modules, reason).await;
+        let mut last_activity = Instant::now();
+        let mut fired = 0;
+        loop {
+            let next_stage = async {
+                match stages.get(fired) {
+                    Some(stage) if reason.is_none() => {
+                        let deadline = last_activity + Duration::from_millis(stage.timeout);
+                        tokio::time::sleep_until(deadline).await
+                    }
+                    _ => std::future::pending().await,
+                }
+            };
+            let mut activity = false;
+            tokio::select! {
+                event = events.recv() => {
+                    match event {
+                        Ok(event) => {
+                            if let Some(focused) = current_program::from_event(&event) {
+                                inhibitors.focused = focused;
+                            }
+                        }
+                        // A missed event could have changed focus or
+                        // fullscreen, so ask sway again.
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            if let Ok(focused) = current_program::handle(HashMap::new()).await {
+                                inhibitors.focused = focused;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                    activity = true;
+                }
+
+                changed = manual.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    inhibitors.manual = *manual.borrow_and_update();
+                }
+
+                _ = audio_timer.tick(), if check_audio => {
+                    inhibitors.audio = audio_playing().await;
+                }
+
+                _ = next_stage => {
+                    run(&stages[fired].command);
+                    fired += 1;
+                }
+            }
+
+            let new_reason = inhibitors.reason(&inhibit);
+            if new_reason != reason {
+                reason = new_reason;
+                report(&control, &modules, reason).await;
+                // The stages start counting again once the inhibitor goes away.
+                activity = true;
+            }
+            if activity {
+                for stage in stages[..fired].iter().rev() {
+                    if let Some(command) = &stage.resume_command {
+                        run(command);
+                    }
+                }
+                fired = 0;
+                last_activity = Instant::now();
+            }
+        }
+    });
+}
@@ -0,0 +1,290 @@
+use crate::process;
+use futures::{SinkExt, StreamExt};
+use std::env;
+use std::error::Error;
+use std::result::Result as StdResult;
+use std::time::Duration;
+use swayipc::{CommandOutcome, Event};
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, watch};
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+const MAGIC: &[u8] = b"i3-ipc";
+const HEADER_LEN: usize = MAGIC.len() + 8;
+/// Far more than any real reply, even a big `get_tree`. Anything longer is a
+/// corrupt or desynced header, not something to allocate for.
+const MAX_PAYLOAD_LEN: usize = 16 << 20;
+/// Set on the type of every event, as opposed to a reply to a request.
+const EVENT_BIT: u32 = 1 << 31;
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+pub const RUN_COMMAND: u32 = 0;
+pub const GET_WORKSPACES: u32 = 1;
+pub const SUBSCRIBE: u32 = 2;
+pub const GET_TREE: u32 = 4;
+pub const GET_BINDING_STATE: u32 = 12;
+pub const GET_INPUTS: u32 = 100;
+
+/// One i3-ipc message: a message or event type and its JSON payload.
+#[derive(Debug)]
+pub struct Message {
+    pub msg_type: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(msg_type: u32, payload: impl Into<Vec<u8>>) -> Self {
+        Message {
+            msg_type,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn is_event(&self) -> bool {
+        self.msg_type & EVENT_BIT != 0
+    }
+}
+
+/// Frames the i3-ipc wire format: the `i3-ipc` magic string, the payload
+/// length and message type as little endian u32s, then the payload.
+pub struct Codec;
+
+impl Decoder for Codec {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Self::Error> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        if &src[..MAGIC.len()] != MAGIC {
+            return Err(std::io::Error::other("bad magic in sway IPC message"));
+        }
+        let mut header = &src[MAGIC.len()..HEADER_LEN];
+        let len = header.get_u32_le() as usize;
+        let msg_type = header.get_u32_le();
+        if len > MAX_PAYLOAD_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("sway IPC message of {} bytes is too long", len),
+            ));
+        }
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let payload = src.split_to(len).to_vec();
+        Ok(Some(Message { msg_type, payload }))
+    }
+}
+
+impl Encoder<Message> for Codec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(HEADER_LEN + msg.payload.len());
+        dst.put_slice(MAGIC);
+        dst.put_u32_le(msg.payload.len() as u32);
+        dst.put_u32_le(msg.msg_type);
+        dst.put_slice(&msg.payload);
+        Ok(())
+    }
+}
+
+pub type Connection = Framed<UnixStream, Codec>;
+
+/// Finds the IPC socket: sway's first, then i3's, and finally whatever
+/// `i3 --get-socketpath` reports.
+async fn socket_path() -> StdResult<String, Box<dyn Error + Send + Sync>> {
+    for var in ["SWAYSOCK", "I3SOCK"] {
+        if let Ok(path) = env::var(var) {
+            return Ok(path);
+        }
+    }
+    let output = process::command("i3").arg("--get-socketpath").output().await.ok();
+    match output {
+        Some(output) if output.status.success() => {
+            Ok(String::from_utf8(output.stdout)?.trim().to_string())
+        }
+        _ => Err("no SWAYSOCK or I3SOCK set and i3 doesn't know its socket".into()),
+    }
+}
+
+pub async fn connect() -> StdResult<Connection, Box<dyn Error + Send + Sync>> {
+    let stream = UnixStream::connect(socket_path().await?).await?;
+    Ok(Framed::new(stream, Codec))
+}
+
+/// Sends a single request on its own connection and returns the reply payload.
+pub async fn request(
+    msg_type: u32,
+    payload: &str,
+) -> StdResult<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut connection = connect().await?;
+    connection.send(Message::new(msg_type, payload)).await?;
+    while let Some(msg) = connection.next().await {
+        let msg = msg?;
+        if msg.msg_type == msg_type {
+            return Ok(msg.payload);
+        }
+    }
+    Err("sway closed the connection before replying".into())
+}
+
+/// Quotes an argument for a sway command, so paths and names with spaces
+/// stay one argument.
+pub fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Runs sway commands, separated by `;` or `,` like in the sway config,
+/// failing with sway's error message if any of them did.
+pub async fn run_command(command: &str) -> StdResult<(), Box<dyn Error + Send + Sync>> {
+    let reply = request(RUN_COMMAND, command).await?;
+    let outcomes: Vec<CommandOutcome> = serde_json::from_slice(&reply)?;
+    for outcome in outcomes {
+        CommandOutcome::decode(outcome).map_err(|e| format!("`{}`: {}", command, e))?;
+    }
+    Ok(())
+}
+
+/// Opens a connection subscribed to the given event types.
+async fn subscribe(events: &[&str]) -> StdResult<Connection, Box<dyn Error + Send + Sync>> {
+    let mut connection = connect().await?;
+    let payload = serde_json::to_string(events)?;
+    connection.send(Message::new(SUBSCRIBE, payload)).await?;
+    let reply = connection
+        .next()
+        .await
+        .ok_or("sway closed the connection before replying")??;
+    let reply: serde_json::Value = serde_json::from_slice(&reply.payload)?;
+    if reply["success"] != serde_json::Value::Bool(true) {
+        return Err(format!("sway refused the subscription: {}", reply).into());
+    }
+    Ok(connection)
+}
+
+/// Decodes every event on a subscribed connection and hands it to all
+/// receivers of `chan`, until the connection goes away.
+async fn forward_events(connection: &mut Connection, chan: &broadcast::Sender<Event>) {
+    while let Some(msg) = connection.next().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("lost the sway IPC connection: {}", e);
+                return;
+            }
+        };
+        if !msg.is_event() {
+            continue;
+        }
+        match Event::decode((msg.msg_type, msg.payload)) {
+            Ok(event) => {
+                // Nobody listening right now is fine, so the error is ignored.
+                let _ = chan.send(event);
+            }
+            Err(e) => eprintln!("could not decode sway event: {}", e),
+        }
+    }
+}
+
+/// Keeps a subscription to `events` open, reconnecting with exponential
+/// backoff whenever it fails or is closed. `connected` says whether the
+/// subscription is currently up.
+pub fn listen(
+    events: &'static [&'static str],
+    chan: broadcast::Sender<Event>,
+    connected: watch::Sender<Option<bool>>,
+) {
+    let set_connected = move |up: bool| {
+        connected.send_if_modified(|current| {
+            let changed = *current != Some(up);
+            *current = Some(up);
+            changed
+        });
+    };
+    tokio::task::spawn(async move {
+        let mut backoff = RECONNECT_MIN;
+        loop {
+            match subscribe(events).await {
+                Ok(mut connection) => {
+                    backoff = RECONNECT_MIN;
+                    set_connected(true);
+                    forward_events(&mut connection, &chan).await;
+                }
+                Err(e) => eprintln!("could not subscribe to sway events: {}", e),
+            }
+            set_connected(false);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(RECONNECT_MAX);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(msg_type: u32, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        Codec.encode(Message::new(msg_type, payload), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn encodes_the_i3_ipc_header() {
+        let buf = frame(RUN_COMMAND, b"exit");
+        assert_eq!(&buf[..], b"i3-ipc\x04\x00\x00\x00\x00\x00\x00\x00exit");
+    }
+
+    #[test]
+    fn waits_for_a_whole_header() {
+        let mut buf = BytesMut::from(&frame(GET_TREE, b"{}")[..HEADER_LEN - 1]);
+        assert!(Codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), HEADER_LEN - 1);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut buf = frame(GET_TREE, b"{}");
+        buf[0] = b'x';
+        assert!(Codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_payloads() {
+        let mut buf = frame(GET_TREE, b"");
+        buf[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = Codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reassembles_a_payload_split_across_reads() {
+        let whole = frame(EVENT_BIT | 3, br#"{"change":"focus"}"#);
+        let mut buf = BytesMut::new();
+        for chunk in whole.chunks(5) {
+            assert!(Codec.decode(&mut buf).unwrap().is_none());
+            buf.extend_from_slice(chunk);
+        }
+        let msg = Codec.decode(&mut buf).unwrap().unwrap();
+        assert!(msg.is_event());
+        assert_eq!(msg.msg_type, EVENT_BIT | 3);
+        assert_eq!(msg.payload, br#"{"change":"focus"}"#);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decodes_back_to_back_messages() {
+        let mut buf = frame(SUBSCRIBE, b"[]");
+        buf.extend_from_slice(&frame(GET_INPUTS, b""));
+        let first = Codec.decode(&mut buf).unwrap().unwrap();
+        let second = Codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((first.msg_type, &first.payload[..]), (SUBSCRIBE, &b"[]"[..]));
+        assert_eq!((second.msg_type, &second.payload[..]), (GET_INPUTS, &b""[..]));
+        assert!(Codec.decode(&mut buf).unwrap().is_none());
+    }
+}
@@ -1,53 +1,106 @@
-use std::env;
 use clap::Parser;
 use futures::FutureExt;
-use serde_json::Value;
 use std::error::Error;
-use std::{collections::HashMap, fs::read_to_string, fs::write};
-use tokio::io::{AsyncBufReadExt, BufReader, Stdin, AsyncReadExt, AsyncWriteExt};
+use std::{collections::HashMap, fs::read_to_string};
+use tokio::io::{AsyncBufReadExt, BufReader, Stdin};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinHandle;
+mod cli;
+mod click;
+mod control;
+mod history;
+mod idle;
+mod ipc;
+mod persist;
+mod process;
 mod types;
 mod handlers;
 use futures::future::join_all;
 use std::future::Future;
 use std::pin::Pin;
 use std::result::Result as StdResult;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use types::Meta;
-use std::os::unix::net::UnixStream as StdUnixStream;
-use tokio::net::UnixStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use handlers::Render;
+use types::{Control, Meta, RestorePolicy, Schedule};
 
 macro_rules! boxed_handler {
     ($path:path) => {
         || -> Pin<Box<dyn Future<Output = _> + Send>> { Box::pin($path()) }
     };
+    ($path:path, $($arg:ident),+) => {
+        |$($arg),+| -> Pin<Box<dyn Future<Output = _> + Send>> { Box::pin($path($($arg),+)) }
+    };
 }
 
-fn get_handler(my_type: &str) -> (handlers::BoxedHandler, handlers::RenderFn) {
+fn get_handler(my_type: &str) -> (handlers::BoxedHandler, Render) {
     match my_type {
-        "date" => (boxed_handler!(handlers::date::handle), handlers::date::render),
+        "date" => (
+            boxed_handler!(handlers::date::handle, settings),
+            Render::Text(handlers::date::render),
+        ),
         "battery" => (
-            boxed_handler!(handlers::battery::handle),
-            handlers::battery::render,
+            boxed_handler!(handlers::battery::handle, settings),
+            Render::Text(handlers::battery::render),
+        ),
+        "wifi" => (
+            boxed_handler!(handlers::wifi::handle, settings),
+            Render::Text(handlers::wifi::render),
+        ),
+        "volume" => (
+            boxed_handler!(handlers::volume::handle, settings),
+            Render::Text(handlers::volume::render),
+        ),
+        "quote" => (
+            boxed_handler!(handlers::quote::handle, settings),
+            Render::Text(handlers::quote::render),
         ),
-        "wifi" => (boxed_handler!(handlers::wifi::handle), handlers::wifi::render),
-        "volume" => (boxed_handler!(handlers::volume::handle), handlers::volume::render),
-        "quote" => (boxed_handler!(handlers::quote::handle), handlers::quote::render),
         "current" => (
-            boxed_handler!(handlers::current_program::handle),
-            handlers::current_program::render,
+            boxed_handler!(handlers::current_program::handle, settings),
+            Render::Text(handlers::current_program::render),
+        ),
+        "idle" => (
+            boxed_handler!(handlers::idle::handle, settings),
+            Render::Text(handlers::idle::render),
+        ),
+        "workspaces" => (
+            boxed_handler!(handlers::workspaces::handle, settings),
+            Render::Blocks(handlers::workspaces::render),
+        ),
+        "mode" => (
+            boxed_handler!(handlers::mode::handle, settings),
+            Render::Blocks(handlers::mode::render),
+        ),
+        "keyboard_layout" => (
+            boxed_handler!(handlers::keyboard_layout::handle, settings),
+            Render::Blocks(handlers::keyboard_layout::render),
         ),
         "bgchange" => (
-            boxed_handler!(handlers::bg_changer::handle),
-            handlers::bg_changer::render,
+            boxed_handler!(handlers::bg_changer::handle, settings),
+            Render::Text(handlers::bg_changer::render),
+        ),
+        _ => (
+            boxed_handler!(handlers::noop::handle, settings),
+            Render::Text(handlers::noop::render),
         ),
-        _ => (boxed_handler!(handlers::noop::handle), handlers::noop::render),
     }
 }
 
+const STOP_SIGNAL: SignalKind = SignalKind::user_defined1();
+const CONT_SIGNAL: SignalKind = SignalKind::user_defined2();
+
 async fn render(mut chan: Receiver<Vec<types::Out>>) {
-    println!("{}", "{\"version\":1, \"click_events\":true}");
+    let header = types::Header {
+        version: 1,
+        click_events: true,
+        stop_signal: STOP_SIGNAL.as_raw_value(),
+        cont_signal: CONT_SIGNAL.as_raw_value(),
+    };
+    if let Ok(header_json) = serde_json::to_string(&header) {
+        println!("{}", header_json);
+    }
     println!("[");
     println!("[],");
     tokio::task::spawn(async move {
@@ -60,50 +113,115 @@ async fn render(mut chan: Receiver<Vec<types::Out>>) {
     });
 }
 
-fn get_mouse_handler(x: &str) -> handlers::MouseBoxedHandler {
-    match x {
-        "wifi" => boxed_handler!(handlers::wifi_click::click_handle),
-        "volume" => boxed_handler!(handlers::volume_click::click_handle),
-        "current" => boxed_handler!(handlers::prog_click::click_handle),
-        _ => boxed_handler!(handlers::mouse_noop::click_handle),
+fn get_mouse_handler(my_type: &str) -> handlers::MouseBoxedHandler {
+    match my_type {
+        "date" => boxed_handler!(handlers::date::click_handle, event, settings, data, control),
+        "battery" => {
+            boxed_handler!(handlers::battery::click_handle, event, settings, data, control)
+        }
+        "volume" => {
+            boxed_handler!(handlers::volume_click::click_handle, event, settings, data, control)
+        }
+        "workspaces" => {
+            boxed_handler!(handlers::workspaces::click_handle, event, settings, data, control)
+        }
+        "keyboard_layout" => {
+            boxed_handler!(handlers::keyboard_layout::click_handle, event, settings, data, control)
+        }
+        _ => boxed_handler!(handlers::mouse_noop::click_handle, event, settings, data, control),
     }
 }
 
-fn mouse_listener(chan: Sender<handlers::MouseBoxedHandler>, reader: BufReader<Stdin>) {
+fn mouse_listener(chan: Sender<types::ClickEvent>, reader: BufReader<Stdin>) {
     let mut lines = reader.lines();
 
     tokio::task::spawn(async move {
         while let Ok(Some(line)) = lines.next_line().await {
-            let line = if line.chars().nth(0).unwrap_or(' ') == ',' {
+            let line = if line.starts_with(',') {
                 line.trim_start_matches(',')
             } else {
                 line.as_str()
             };
             //let line = line.trim_start_matches(',');
-            if let Ok(value) = serde_json::from_str::<Value>(line) {
-                let instance = &value["instance"];
-                let inst = instance.as_str().unwrap_or("");
-                let h = get_mouse_handler(inst);
-                let _ = chan.send(h).await;
+            if let Ok(event) = serde_json::from_str::<types::ClickEvent>(line) {
+                let _ = chan.send(event).await;
             }
         }
     });
 }
 
-fn write_state(mut chan: Receiver<HashMap<String, Meta>>, out_path: String, buffer_size: i32) {
+fn click_dispatcher(
+    mut chan: Receiver<types::ClickEvent>,
+    config: Arc<types::Config>,
+    state: watch::Receiver<HashMap<String, Meta>>,
+    control: Sender<Control>,
+) {
+    let debounce = Duration::from_millis(config.click_debounce_time.unwrap_or(30));
+    let double_click_time = Duration::from_millis(config.double_click_time.unwrap_or(250));
+
     tokio::task::spawn(async move {
-        let mut counter = 0;
-        while let Some(msg) = chan.recv().await {
-            if counter % buffer_size == 0 {
-                let out_json = serde_json::to_string(&msg);
-                match out_json {
-                    Ok(x) => {
-                        let _ = write(out_path.as_str(), x);
+        let mut last: Option<(types::ClickEvent, Instant)> = None;
+        // A left click held back while we wait to see if it becomes a double click.
+        let mut pending: Option<(types::ClickEvent, Instant)> = None;
+
+        loop {
+            let next = match &pending {
+                Some((_, deadline)) => tokio::select! {
+                    event = chan.recv() => event,
+                    _ = tokio::time::sleep_until((*deadline).into()) => {
+                        if let Some((event, _)) = pending.take() {
+                            dispatch_click(event, &config, &state.borrow(), &control);
+                        }
+                        continue;
                     }
-                    Err(_) => (),
-                };
+                },
+                None => chan.recv().await,
+            };
+            let Some(mut event) = next else {
+                break;
+            };
+
+            let now = Instant::now();
+            // Each scroll notch is its own press, and a fast scroll sends
+            // them closer together than any debounce.
+            let scroll = matches!(
+                event.button(),
+                types::Button::ScrollUp
+                    | types::Button::ScrollDown
+                    | types::Button::ScrollLeft
+                    | types::Button::ScrollRight
+            );
+            if let Some((prev, at)) = &last {
+                if !scroll
+                    && click::same_target(prev, &event)
+                    && now.duration_since(*at) < debounce
+                {
+                    continue;
+                }
+            }
+            last = Some((event.clone(), now));
+
+            let wants_double_click = |event: &types::ClickEvent| {
+                config
+                    .modules
+                    .iter()
+                    .find(|m| m.name == event.name)
+                    .is_some_and(|m| click::wants_double_click(&m.on_click, event))
+            };
+            if let Some((prev, _)) = pending.take() {
+                if click::same_target(&prev, &event) && wants_double_click(&event) {
+                    event.double_click = true;
+                    dispatch_click(event, &config, &state.borrow(), &control);
+                    continue;
+                }
+                dispatch_click(prev, &config, &state.borrow(), &control);
+            }
+
+            if event.button() == types::Button::Left && wants_double_click(&event) {
+                pending = Some((event, now + double_click_time));
+            } else {
+                dispatch_click(event, &config, &state.borrow(), &control);
             }
-            counter = (counter + 1) % buffer_size;
         }
     });
 }
@@ -116,6 +234,150 @@ fn write_state(mut chan: Receiver<HashMap<String, Meta>>, out_path: String, buff
 pub struct Args {
     #[arg(short, long)]
     pub config: String,
+    #[command(subcommand)]
+    pub command: Option<cli::Command>,
+}
+
+fn signal_listener(chan: Sender<Control>) -> std::io::Result<()> {
+    let mut stop = signal(STOP_SIGNAL)?;
+    let mut cont = signal(CONT_SIGNAL)?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    tokio::task::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                Some(_) = stop.recv() => Control::Stop,
+                Some(_) = cont.recv() => Control::Cont,
+                Some(_) = terminate.recv() => Control::Shutdown,
+                Some(_) = interrupt.recv() => Control::Shutdown,
+                else => break,
+            };
+            if chan.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(Default)]
+struct LoopFlags {
+    hidden: bool,
+    shutdown: bool,
+    /// Set when persisted module state changed since it was last handed to
+    /// the writer.
+    dirty: bool,
+}
+
+fn persists(config: &types::Config, name: &str) -> bool {
+    config
+        .modules
+        .iter()
+        .find(|m| m.name == name)
+        .is_some_and(|m| m.persist.unwrap_or(true))
+}
+
+/// The part of the state that goes to disk, leaving out `persist: false` modules.
+fn persisted_state(
+    config: &types::Config,
+    state: &HashMap<String, Meta>,
+) -> HashMap<String, Meta> {
+    state
+        .iter()
+        .filter(|(name, _)| persists(config, name))
+        .map(|(name, meta)| (name.clone(), meta.clone()))
+        .collect()
+}
+
+fn handle_control(
+    msg: Control,
+    flags: &mut LoopFlags,
+    state: &mut HashMap<String, Meta>,
+    schedules: &mut HashMap<String, Schedule>,
+    config: &types::Config,
+    manual_inhibit: &watch::Sender<bool>,
+) {
+    match msg {
+        Control::Stop => flags.hidden = true,
+        Control::Cont => flags.hidden = false,
+        Control::Shutdown => flags.shutdown = true,
+        Control::Update(name, data) => {
+            // Pushed data can arrive before the module's first tick.
+            if config.modules.iter().any(|m| m.name == name) {
+                state.entry(name.clone()).or_default().data.extend(data);
+                flags.dirty |= persists(config, &name);
+            }
+        }
+        Control::Refresh(name) => {
+            // Forgetting about a running handler lets the next tick abort it
+            // and start a fresh one.
+            if let Some(schedule) = schedules.get_mut(&name) {
+                schedule.next_run = Instant::now();
+                schedule.started = None;
+            }
+        }
+        Control::ToggleIdleInhibit => {
+            manual_inhibit.send_modify(|inhibit| *inhibit = !*inhibit);
+        }
+        Control::State(request, reply) => {
+            let changes = !matches!(request, control::StateRequest::Show { .. });
+            let cleared = match &request {
+                control::StateRequest::Clear { module } => Some(module.clone()),
+                _ => None,
+            };
+            let known = |name: &str| config.modules.iter().any(|m| m.name == name);
+            let response = control::apply(request, state, known);
+            if let (Ok(touched), true) = (&response, changes) {
+                for name in touched.keys() {
+                    flags.dirty |= persists(config, name);
+                }
+            }
+            // A cleared module has nothing to show until its handler runs again.
+            if let (Ok(_), Some(name)) = (&response, cleared) {
+                if let Some(schedule) = schedules.get_mut(&name) {
+                    schedule.next_run = Instant::now();
+                    schedule.started = None;
+                }
+            }
+            let _ = reply.send(response);
+        }
+    }
+}
+
+fn dispatch_click(
+    event: types::ClickEvent,
+    config: &types::Config,
+    state: &HashMap<String, Meta>,
+    control: &Sender<Control>,
+) {
+    let Some(module_config) = config.modules.iter().find(|m| m.name == event.name) else {
+        return;
+    };
+    if let Some(action) = click::find_action(&module_config.on_click, &event) {
+        let action = action.clone();
+        let name = module_config.name.clone();
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = click::run_action(action, name.clone(), control).await {
+                eprintln!("click action for {} failed: {}", name, e);
+            }
+        });
+    } else {
+        let mouse_handle = get_mouse_handler(module_config.kind());
+        let settings = module_config.settings.clone();
+        let data = state
+            .get(&module_config.name)
+            .map(|meta| meta.data.clone())
+            .unwrap_or_default();
+        let control = control.clone();
+        let name = module_config.name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mouse_handle(event, settings, data, control).await {
+                eprintln!("click handler for {} failed: {}", name, e);
+            }
+        });
+    }
 }
 
 fn possible_abort_task<T>(z: Option<JoinHandle<T>>) {
@@ -125,164 +387,292 @@ fn possible_abort_task<T>(z: Option<JoinHandle<T>>) {
 
 }
 
-fn reset_state(state: &mut Meta) {
-    state.is_processing = false;
-    state.start_time = Duration::ZERO;
+/// Builds the startup schedule for a module according to its restore
+/// policy, dropping its saved data if the policy says so.
+fn restore_schedule(
+    module_config: &types::ModuleConfig,
+    state: &mut HashMap<String, Meta>,
+    now: Instant,
+) -> Schedule {
+    let ttl = Duration::from_millis(module_config.ttl);
+    let next_run = match module_config.restore.unwrap_or_default() {
+        RestorePolicy::ShowCachedThenRefresh => now,
+        RestorePolicy::Discard => {
+            state.remove(&module_config.name);
+            now
+        }
+        RestorePolicy::TrustUntilTtl => {
+            let age = state.get(&module_config.name).and_then(|meta| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()?
+                    .checked_sub(meta.updated)
+            });
+            match age {
+                Some(age) if age < ttl => now + (ttl - age),
+                _ => now,
+            }
+        }
+    };
+    Schedule {
+        next_run,
+        started: None,
+    }
 }
 
-const SWAY_MAGIC: &[u8] = b"i3-ipc";
-const SUBSCRIBE: u32 = 2;
-
-async fn get_stream() -> StdResult<UnixStream, Box<dyn Error>> {
-    let socket_path = env::var("SWAYSOCK")?;
-//    let std_stream = StdUnixStream::connect(socket_path)?;
-    let mut stream = UnixStream::connect(socket_path).await?;
+const SUBSCRIPTIONS: &[&str] = &["window", "workspace", "mode", "input", "binding", "tick"];
 
-    let payload = r#"["window", "input", "binding"]"#;
-    let payload_bytes = payload.as_bytes();
-
-    stream.write_all(SWAY_MAGIC).await?;
-    stream.write_u32_le(payload_bytes.len() as u32).await?;
-    stream.write_u32_le(SUBSCRIBE).await?;
-    stream.write_all(payload_bytes).await?;
-    stream.flush().await?;
-    Ok(stream)
+/// Module types that get their data from the sway IPC socket.
+const IPC_MODULES: &[&str] = &["current", "workspaces", "mode", "keyboard_layout"];
 
+/// Marks the IPC modules unavailable while the sway connection is down, and
+/// refreshes them once it is back since they missed events in between.
+fn ipc_status_listener(
+    mut connected: watch::Receiver<Option<bool>>,
+    config: Arc<types::Config>,
+    control: Sender<Control>,
+) {
+    tokio::task::spawn(async move {
+        while connected.changed().await.is_ok() {
+            let Some(up) = *connected.borrow_and_update() else {
+                continue;
+            };
+            let data = HashMap::from([(String::from("available"), up.to_string())]);
+            for module in ipc_modules(&config) {
+                let _ = control.send(Control::Update(module.name.clone(), data.clone())).await;
+                if up {
+                    let _ = control.send(Control::Refresh(module.name.clone())).await;
+                }
+            }
+        }
+    });
 }
 
+/// Module types whose data is pushed straight from sway events.
+const PUSHED_MODULES: &[(&str, handlers::EventFn)] = &[
+    ("current", handlers::current_program::from_event),
+    ("mode", handlers::mode::from_event),
+    ("keyboard_layout", handlers::keyboard_layout::from_event),
+];
 
+fn ipc_modules(config: &types::Config) -> impl Iterator<Item = &types::ModuleConfig> {
+    config.modules.iter().filter(|m| IPC_MODULES.contains(&m.kind()))
+}
 
-fn listen_on_swap_ipc(mut stream: UnixStream, timeout : Duration) {
+fn modules_of<'a>(
+    config: &'a types::Config,
+    kind: &'a str,
+) -> impl Iterator<Item = &'a types::ModuleConfig> {
+    config.modules.iter().filter(move |m| m.kind() == kind)
+}
 
+/// Keeps the IPC modules up to date from sway events: focus, mode and
+/// keyboard layout changes are pushed to their modules as they happen, and
+/// `workspaces` modules refresh whenever a workspace changes. Tick events
+/// carry commands for the bar itself.
+fn ipc_event_listener(
+    mut events: broadcast::Receiver<swayipc::Event>,
+    config: Arc<types::Config>,
+    control: Sender<Control>,
+) {
     tokio::task::spawn(async move {
         loop {
-            let mut header = [0u8; 14];
-            tokio::select! {
-                _result = stream.read_exact(&mut header) => {
-                    ()
+            let event = match events.recv().await {
+                Ok(event) => event,
+                // Any of the missed events could have changed what an IPC
+                // module shows, so they all have to look again.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    for module in ipc_modules(&config) {
+                        let _ = control.send(Control::Refresh(module.name.clone())).await;
+                    }
+                    continue;
                 }
-
-                _ = tokio::time::sleep(timeout) => {
-                    let _ = std::process::Command::new("systemctl")
-                        .arg("suspend")
-                        .spawn();
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            for (kind, from_event) in PUSHED_MODULES {
+                let Some(data) = from_event(&event) else {
+                    continue;
+                };
+                for module in modules_of(&config, kind) {
+                    let _ = control.send(Control::Update(module.name.clone(), data.clone())).await;
+                }
+            }
+            if let swayipc::Event::Tick(tick) = &event {
+                if let Some(msg) = control::from_tick(&tick.payload) {
+                    let _ = control.send(msg).await;
+                }
+            }
+            if matches!(event, swayipc::Event::Workspace(_)) {
+                for module in modules_of(&config, "workspaces") {
+                    let _ = control.send(Control::Refresh(module.name.clone())).await;
                 }
             }
         }
     });
 }
 
-
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> StdResult<(), Box<dyn Error>> {
 
     let stdin = tokio::io::stdin(); // 
     let reader = BufReader::new(stdin);
     let args = Args::parse();
-    let path = args.config;
-    let config_str = read_to_string(path)?;
-    let config: types::Config = serde_json::from_str(config_str.as_str())?;
-    let stream = get_stream().await?; 
-
-    if let Some(suspend_timeout_ms) = config.suspend_time {
-        listen_on_swap_ipc(stream, Duration::from_millis(suspend_timeout_ms)); 
+    let config_str = read_to_string(&args.config)?;
+    let config: Arc<types::Config> = Arc::new(serde_json::from_str(config_str.as_str())?);
+    if let Some(command) = args.command {
+        return cli::run(command, &config).map_err(|e| e as Box<dyn Error>);
     }
-
-    let init_state_str = match read_to_string(config.persist.path.to_string()) {
-        Ok(my_str) => my_str,
-        Err(_) => String::from("{}"),
+    // Held until the process exits, which is what releases the lock. Like a
+    // broken state file, a lock that can't be taken doesn't stop the bar.
+    let _instance = match persist::lock_instance(
+        &config.persist.path,
+        config.persist.instance.unwrap_or_default(),
+    ) {
+        Ok(file) => Some(file),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Err(e.to_string().into()),
+        Err(e) => {
+            eprintln!("running without a lock on {}.lock: {}", config.persist.path, e);
+            None
+        }
     };
-    let mut state: HashMap<String, Meta> = serde_json::from_str(init_state_str.as_str())?;
+
+    let mut state = persist::load_state(&config.persist.path);
+    let startup = Instant::now();
+    let mut schedules: HashMap<String, Schedule> = config
+        .modules
+        .iter()
+        .map(|m| (m.name.clone(), restore_schedule(m, &mut state, startup)))
+        .collect();
 
     let poll_time = Duration::from_millis(config.poll_time.unwrap_or(100));
+    // The header advertises the stop and cont signals, so they have to be
+    // handled before it goes out.
+    let (control_sender, mut control_receiver) = tokio::sync::mpsc::channel::<Control>(10);
+    signal_listener(control_sender.clone())?;
     let (render_sender, render_receiver) = tokio::sync::mpsc::channel::<Vec<types::Out>>(5);
     render(render_receiver).await;
 
     let (state_sender, state_receiver) = tokio::sync::mpsc::channel::<HashMap<String, Meta>>(5);
 
-    let (mouse_sender, mut mouse_receiver) =
-        tokio::sync::mpsc::channel::<handlers::MouseBoxedHandler>(10);
-    write_state(
+    let (mouse_sender, mouse_receiver) = tokio::sync::mpsc::channel::<types::ClickEvent>(10);
+    let (snapshot_sender, snapshot_receiver) = watch::channel(state.clone());
+    let socket_path = control::socket_path(&config.persist.path);
+    if let Err(e) = control::listen(socket_path.clone(), control_sender.clone()) {
+        eprintln!("could not listen on {}: {}", socket_path, e);
+    }
+    let (event_sender, _) = broadcast::channel::<swayipc::Event>(32);
+    ipc_event_listener(event_sender.subscribe(), config.clone(), control_sender.clone());
+    let (manual_inhibit, manual_inhibit_receiver) = watch::channel(false);
+    idle::idle_listener(
+        event_sender.subscribe(),
+        manual_inhibit_receiver,
+        config.clone(),
+        control_sender.clone(),
+    );
+    let (connected_sender, connected_receiver) = watch::channel(None);
+    ipc_status_listener(connected_receiver, config.clone(), control_sender.clone());
+    ipc::listen(SUBSCRIPTIONS, event_sender, connected_sender);
+    let writer = persist::write_state(
         state_receiver,
-        config.persist.path,
-        config.persist.buffer_size,
+        config.persist.path.clone(),
+        config.persist.format.unwrap_or_default(),
+        Duration::from_millis(config.persist.min_interval_ms.unwrap_or(1000)),
+        Duration::from_millis(config.persist.max_interval_ms.unwrap_or(10000)),
     );
+    let (history_sender, history_receiver) =
+        tokio::sync::mpsc::channel::<(String, HashMap<String, String>)>(10);
+    history::write_history(history_receiver, config.clone());
     let mut futures = HashMap::<String, JoinHandle<Option<HashMap<String, String>>>>::new();
 
     mouse_listener(mouse_sender, reader);
+    click_dispatcher(
+        mouse_receiver,
+        config.clone(),
+        snapshot_receiver,
+        control_sender.clone(),
+    );
+    let mut flags = LoopFlags::default();
     loop {
-        if let Some(Some(mouse_handle)) = mouse_receiver.recv().now_or_never() {
-            tokio::spawn(async move {
-                let _ = mouse_handle().await;
-            });
+        while let Ok(msg) = control_receiver.try_recv() {
+            handle_control(msg, &mut flags, &mut state, &mut schedules, &config, &manual_inhibit);
         }
-        let loop_begin = std::time::Instant::now();
+        if flags.shutdown {
+            break;
+        }
+        let loop_begin = Instant::now();
         let mut futs = Vec::new();
 
         for module_config in &config.modules {
             let timeout_ms = module_config.timeout.unwrap_or(config.default_timeout);
             let timeout = Duration::from_millis(timeout_ms);
-            let default = Meta {
-                is_processing: false,
-                start_time: Duration::ZERO,
-                data: HashMap::new(),
-            };
-            let mut state = state.remove(&module_config.name).unwrap_or(default);
+            let mut state = state.remove(&module_config.name).unwrap_or_default();
+            let mut schedule = schedules
+                .remove(&module_config.name)
+                .unwrap_or_else(|| Schedule::new(loop_begin));
             let ttl = Duration::from_millis(module_config.ttl);
             let display = module_config.display.unwrap_or(true);
+            let hidden = flags.hidden;
+            let paused = hidden && !module_config.run_while_hidden.unwrap_or(true);
 
             let old_fut = futures.remove(&module_config.name);
 
             let fin = async move {
                 let name = module_config.name.as_str();
-                let (handler, render) = get_handler(name);
-                //let handler2 = get_handler(name);
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards");
-                let expire_time = state.start_time + ttl;
-                let mut my_f = if !state.is_processing && now > expire_time {
+                let (handler, render) = get_handler(module_config.kind());
+                let settings = module_config.settings.clone();
+                let now = Instant::now();
+                let due = !paused && schedule.started.is_none() && now >= schedule.next_run;
+                let mut my_f = if due {
                     possible_abort_task(old_fut);
 
                     let fut = tokio::spawn(async move {
-                        let f = handler().await;
-                        match f {
-                            Ok(ff) => Some(ff),
-                            Err(_) => None,
-                        }
+                        handler(settings).await.ok()
                     });
-                    state.is_processing = true;
-                    state.start_time = now;
+                    schedule.started = Some(now);
+                    schedule.next_run = now + ttl;
                     Some(fut)
                 } else {
                     old_fut
                 };
 
+                let mut updated = false;
+                let mut changed = false;
                 let new_fut = match my_f.as_mut().and_then(|f| f.now_or_never()) {
-                    Some(Ok(res)) => {
-                        if let Some(res) = res {
+                    Some(res) => {
+                        if let Some(res) = res.ok().flatten() {
+                            changed = res.iter().any(|(k, v)| state.data.get(k) != Some(v));
                             state.data.extend(res);
-                            state.is_processing = false;
+                            state.updated = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or(Duration::ZERO);
+                            updated = true;
+                        } else if let Some(started) = schedule.started {
+                            // A failed run gets retried once its timeout is up
+                            // rather than waiting out the whole ttl.
+                            schedule.next_run = started + timeout;
                         }
+                        schedule.started = None;
                         None
                     }
-                    _ => {
-                        let elapsed = now.checked_sub(state.start_time).unwrap_or(Duration::ZERO);
-                        match (state.is_processing, elapsed < timeout) {
-                            (true, true) => my_f,
-                            (true, false) => {
-                                reset_state(&mut state);
-                                possible_abort_task(my_f);
-                                None
-                            },
-                            (false, _) => my_f
+                    None => match schedule.started {
+                        Some(started) if now.duration_since(started) >= timeout => {
+                            schedule.started = None;
+                            schedule.next_run = now;
+                            possible_abort_task(my_f);
+                            None
                         }
-                    }
+                        _ => my_f,
+                    },
                 };
 
-                let out = display.then(|| render(&state.data));
+                let out = (display && !hidden)
+                    .then(|| render.blocks(&state.data, &module_config.settings));
+                let history = (updated && module_config.history.is_some())
+                    .then(|| state.data.clone());
+                // Only new data is worth a write; the timestamp alone isn't.
+                let dirty = changed && module_config.persist.unwrap_or(true);
 
-                (name.to_string(), state, out, new_fut)
+                (name.to_string(), state, schedule, out, new_fut, history, dirty)
             };
             futs.push(fin);
         }
@@ -291,26 +681,56 @@ async fn main() -> StdResult<(), Box<dyn Error>> {
 
         let out_objs: Vec<types::Out> = values
             .into_iter()
-            .filter_map(|(name, meta, out_str, new_fut)| {
+            .flat_map(|(name, meta, schedule, out_blocks, new_fut, history, dirty)| {
                 state.insert(name.clone(), meta);
+                schedules.insert(name.clone(), schedule);
+                flags.dirty |= dirty;
                 if let Some(f) = new_fut {
                     futures.insert(name.clone(), f);
                 }
+                if let Some(data) = history {
+                    let _ = history_sender.try_send((name.clone(), data));
+                }
 
-                out_str.map(|f| types::Out {
+                out_blocks.into_iter().flatten().map(move |block| types::Out {
                     name: name.clone(),
-                    instance: name,
-                    full_text: f,
+                    instance: block.instance.unwrap_or_else(|| name.clone()),
+                    full_text: block.full_text,
+                    color: block.color,
+                    background: block.background,
+                    border: block.border,
+                    urgent: block.urgent,
                 })
             })
             .collect();
 
-        let a1 = render_sender.send(out_objs);
-        let a2 = state_sender.send(state.clone());
-        let _ = tokio::join!(a1, a2);
+        if !flags.hidden {
+            let _ = render_sender.send(out_objs).await;
+        }
+        snapshot_sender.send_replace(state.clone());
+        if flags.dirty {
+            let _ = state_sender.send(persisted_state(&config, &state)).await;
+            flags.dirty = false;
+        }
 
         let elapsed = loop_begin.elapsed();
         let wait_time = poll_time.checked_sub(elapsed).unwrap_or(Duration::ZERO);
-        tokio::time::sleep(wait_time).await;
+        tokio::select! {
+            _ = tokio::time::sleep(wait_time) => (),
+            Some(msg) = control_receiver.recv() => {
+                handle_control(msg, &mut flags, &mut state, &mut schedules, &config, &manual_inhibit)
+            }
+        }
+    }
+
+    // Dropping the sender makes the writer flush whatever it was holding back.
+    if flags.dirty {
+        let _ = state_sender.send(persisted_state(&config, &state)).await;
     }
+    drop(state_sender);
+    let _ = writer.await;
+    let _ = std::fs::remove_file(&socket_path);
+    // Tokio's stdin is a blocking read that can't be cancelled, so returning
+    // from main would hang until swaybar writes another click.
+    std::process::exit(0);
 }
@@ -0,0 +1,411 @@
+use crate::types::{InstancePolicy, Meta, PersistFormat};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+
+pub type State = HashMap<String, Meta>;
+
+/// A JSON state file always starts with `{`, which MessagePack never uses
+/// as the first byte of a map.
+pub fn detect(bytes: &[u8]) -> PersistFormat {
+    match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => PersistFormat::Json,
+        _ => PersistFormat::Msgpack,
+    }
+}
+
+/// Version of the on-disk layout. Bump it together with a new step in
+/// `migrate` whenever the persisted shape changes incompatibly.
+pub const CURRENT_VERSION: u64 = 3;
+
+#[derive(Serialize)]
+struct Persisted<'a> {
+    version: u64,
+    modules: &'a State,
+}
+
+pub fn encode(
+    state: &State,
+    format: PersistFormat,
+) -> StdResult<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let persisted = Persisted {
+        version: CURRENT_VERSION,
+        modules: state,
+    };
+    Ok(match format {
+        PersistFormat::Json => serde_json::to_vec(&persisted)?,
+        // Named fields keep structs as maps, so they survive field reordering.
+        PersistFormat::Msgpack => rmp_serde::to_vec_named(&persisted)?,
+    })
+}
+
+/// Version 1 files are the bare module map, before the layout carried a
+/// version at all.
+fn version_of(value: &Value) -> u64 {
+    value.get("version").and_then(Value::as_u64).unwrap_or(1)
+}
+
+/// What the clock used to save before it formatted the time itself.
+const LEGACY_DATE_KEYS: &[&str] = &["weekday", "day", "month", "hour", "minutes", "seconds"];
+
+/// Upgrades a decoded file one version at a time until it is current.
+fn migrate(mut value: Value) -> Value {
+    loop {
+        let version = version_of(&value);
+        value = match version {
+            1 => json!({ "version": 2, "modules": value }),
+            // Scheduling state moved out of the file; the last start time is
+            // the closest thing we have to when the data was updated. The
+            // clock's old per-field keys are never written anymore, and new
+            // data is merged over old, so they'd otherwise stay forever.
+            2 => {
+                if let Some(Value::Object(modules)) = value.get_mut("modules") {
+                    for (name, meta) in modules.iter_mut() {
+                        let Some(meta) = meta.as_object_mut() else {
+                            continue;
+                        };
+                        meta.remove("is_processing");
+                        if let Some(start_time) = meta.remove("start_time") {
+                            meta.insert(String::from("updated"), start_time);
+                        }
+                        if name == "date" {
+                            if let Some(Value::Object(data)) = meta.get_mut("data") {
+                                for key in LEGACY_DATE_KEYS {
+                                    data.remove(*key);
+                                }
+                            }
+                        }
+                    }
+                }
+                value["version"] = json!(3);
+                value
+            }
+            _ => return value,
+        };
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> StdResult<State, Box<dyn Error + Send + Sync>> {
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return Ok(HashMap::new());
+    }
+    let value: Value = match detect(bytes) {
+        PersistFormat::Json => serde_json::from_slice(bytes)?,
+        PersistFormat::Msgpack => rmp_serde::from_slice(bytes)?,
+    };
+    if version_of(&value) > CURRENT_VERSION {
+        eprintln!("state file is from a newer version, loading what we can");
+    }
+
+    // Modules are decoded one at a time so a single bad entry only loses
+    // that module instead of the whole file.
+    let mut value = migrate(value);
+    let modules = match value.get_mut("modules").map(Value::take) {
+        Some(Value::Object(modules)) => modules,
+        _ => return Err("state file has no modules".into()),
+    };
+    Ok(modules
+        .into_iter()
+        .filter_map(|(name, meta)| match serde_json::from_value::<Meta>(meta) {
+            Ok(meta) => Some((name, meta)),
+            Err(e) => {
+                eprintln!("dropping saved state for {}: {}", name, e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{}.bak", path)
+}
+
+fn read_state(path: &str) -> StdResult<State, Box<dyn Error + Send + Sync>> {
+    decode(&fs::read(path)?)
+}
+
+/// Loads the state file in whichever format it was written. A missing or
+/// corrupt file falls back to the backup, and then to empty state, so a bad
+/// write never keeps the bar from starting.
+pub fn load_state(path: &str) -> State {
+    match read_state(path) {
+        Ok(state) => state,
+        Err(e) => {
+            if Path::new(path).exists() {
+                eprintln!("could not load {}: {}, trying the backup", path, e);
+            }
+            read_state(&backup_path(path)).unwrap_or_default()
+        }
+    }
+}
+
+/// Replaces the state file without ever leaving a half-written one behind:
+/// the new contents go to a temp file that is synced and renamed over the
+/// old one, which is kept as a backup first.
+pub fn save_state(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+
+    if Path::new(path).exists() {
+        let backup = backup_path(path);
+        let _ = fs::remove_file(&backup);
+        if fs::hard_link(path, &backup).is_err() {
+            fs::copy(path, &backup)?;
+        }
+    }
+    fs::rename(&tmp, path)?;
+
+    // The rename only sticks once the directory entry itself is synced.
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// `flock(2)` on the whole file, retried when a signal interrupts it.
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        // SAFETY: the descriptor stays open for as long as `file` is borrowed.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// How long a takeover waits for the old bar to exit before giving up.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(10);
+const LOCK_RETRY: Duration = Duration::from_millis(100);
+/// How often a waiting bar says it is still waiting.
+const WAIT_REPORT: Duration = Duration::from_secs(10);
+
+/// Polls for the lock until `pid` lets go of it, or until `timeout` is up.
+fn wait_for_lock(file: &File, pid: &str, timeout: Option<Duration>) -> io::Result<()> {
+    let start = Instant::now();
+    let mut reported = start;
+    loop {
+        match flock(file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+        if let Some(timeout) = timeout.filter(|timeout| start.elapsed() >= *timeout) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("swaybar (pid {}) didn't exit within {}s", pid, timeout.as_secs()),
+            ));
+        }
+        if reported.elapsed() >= WAIT_REPORT {
+            eprintln!("still waiting for swaybar (pid {}) to exit", pid);
+            reported = Instant::now();
+        }
+        std::thread::sleep(LOCK_RETRY);
+    }
+}
+
+/// Takes an advisory lock on `<path>.lock` so only one bar writes the state
+/// file. The returned file holds the lock and has to be kept alive. When
+/// another bar holds it, `policy` decides whether to give up, wait for it to
+/// exit, or ask it to exit by sending `SIGTERM` to the pid in the lockfile.
+/// Giving up is reported as a `WouldBlock` error; a takeover that can't
+/// signal the pid or doesn't get the lock within `TAKEOVER_TIMEOUT` fails
+/// with another error.
+pub fn lock_instance(path: &str, policy: InstancePolicy) -> io::Result<File> {
+    let lock_path = format!("{}.lock", path);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)?;
+    match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            let pid = pid.trim();
+            match policy {
+                InstancePolicy::Exit => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("another swaybar (pid {}) holds {}", pid, lock_path),
+                    ));
+                }
+                InstancePolicy::Wait => {
+                    eprintln!("waiting for swaybar (pid {}) to exit", pid);
+                    wait_for_lock(&file, pid, None)?;
+                }
+                InstancePolicy::Takeover => {
+                    // Anything but a positive pid would signal a whole group.
+                    let target: libc::pid_t = pid
+                        .parse()
+                        .ok()
+                        .filter(|target| *target > 0)
+                        .ok_or_else(|| {
+                            io::Error::other(format!("no pid to take over from in {}", lock_path))
+                        })?;
+                    eprintln!("taking over from swaybar (pid {})", pid);
+                    // SAFETY: kill(2) only takes plain integers.
+                    if unsafe { libc::kill(target, libc::SIGTERM) } != 0 {
+                        let e = io::Error::last_os_error();
+                        return Err(io::Error::new(
+                            e.kind(),
+                            format!("could not signal swaybar (pid {}): {}", pid, e),
+                        ));
+                    }
+                    wait_for_lock(&file, pid, Some(TAKEOVER_TIMEOUT))?;
+                }
+            }
+        }
+        Err(e) => return Err(e),
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+async fn flush(path: &str, state: &State, format: PersistFormat) {
+    let bytes = match encode(state, format) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("could not encode state for {}: {}", path, e);
+            return;
+        }
+    };
+    let out_path = path.to_string();
+    match tokio::task::spawn_blocking(move || save_state(&out_path, &bytes)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => eprintln!("could not write {}: {}", path, e),
+        Err(e) => eprintln!("could not write {}: {}", path, e),
+    }
+}
+
+/// Writes the state it receives once changes settle for `min_interval`, but
+/// never holds a change back for longer than `max_interval`. Whatever is
+/// still pending is written once the channel closes, so dropping the sender
+/// flushes.
+pub fn write_state(
+    mut chan: Receiver<State>,
+    out_path: String,
+    format: PersistFormat,
+    min_interval: Duration,
+    max_interval: Duration,
+) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut pending: Option<State> = None;
+        let mut first_change = Instant::now();
+        let mut last_change = Instant::now();
+        loop {
+            let msg = match &pending {
+                Some(_) => {
+                    let deadline = (last_change + min_interval).min(first_change + max_interval);
+                    tokio::select! {
+                        msg = chan.recv() => msg,
+                        _ = tokio::time::sleep_until(deadline.into()) => {
+                            if let Some(state) = pending.take() {
+                                flush(&out_path, &state, format).await;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => chan.recv().await,
+            };
+            let Some(state) = msg else {
+                break;
+            };
+            let now = Instant::now();
+            if pending.is_none() {
+                first_change = now;
+            }
+            last_change = now;
+            pending = Some(state);
+        }
+        if let Some(state) = pending {
+            flush(&out_path, &state, format).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_module() -> Value {
+        json!({
+            "start_time": { "secs": 5, "nanos": 0 },
+            "is_processing": true,
+            "data": { "time": "12:00" }
+        })
+    }
+
+    fn v3_module() -> Value {
+        json!({
+            "updated": { "secs": 5, "nanos": 0 },
+            "data": { "time": "12:00" }
+        })
+    }
+
+    #[test]
+    fn migrates_an_unversioned_file() {
+        let migrated = migrate(json!({ "date": v2_module() }));
+        assert_eq!(migrated, json!({ "version": 3, "modules": { "date": v3_module() } }));
+    }
+
+    #[test]
+    fn migrates_version_2() {
+        let migrated = migrate(json!({ "version": 2, "modules": { "date": v2_module() } }));
+        assert_eq!(migrated, json!({ "version": 3, "modules": { "date": v3_module() } }));
+    }
+
+    #[test]
+    fn drops_the_old_clock_fields() {
+        let date = json!({
+            "start_time": { "secs": 5, "nanos": 0 },
+            "data": { "hour": "12", "minutes": "00", "format": "long" }
+        });
+        let migrated = migrate(json!({ "version": 2, "modules": { "date": date } }));
+        assert_eq!(migrated["modules"]["date"]["data"], json!({ "format": "long" }));
+    }
+
+    #[test]
+    fn leaves_current_and_newer_files_alone() {
+        for version in [CURRENT_VERSION, CURRENT_VERSION + 1] {
+            let file = json!({ "version": version, "modules": { "date": v3_module() } });
+            assert_eq!(migrate(file.clone()), file);
+        }
+    }
+
+    #[test]
+    fn round_trips_both_formats() {
+        let state = State::from([(
+            String::from("date"),
+            Meta {
+                updated: Duration::from_secs(5),
+                data: HashMap::from([(String::from("time"), String::from("12:00"))]),
+            },
+        )]);
+        for format in [PersistFormat::Json, PersistFormat::Msgpack] {
+            let decoded = decode(&encode(&state, format).unwrap()).unwrap();
+            assert_eq!(decoded["date"].updated, state["date"].updated);
+            assert_eq!(decoded["date"].data, state["date"].data);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use tokio::process::Command;
+
+/// A command that starts in its own process group. Swaybar sends its stop and
+/// cont signals to the bar's whole process group, and those would kill any
+/// child that was still running when they arrive.
+pub fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.process_group(0);
+    command
+}
@@ -1,40 +1,294 @@
+use crate::control::{StateRequest, StateResponse};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 
-#[derive(Clone, Serialize, Deserialize)]
+/// What is saved about a module between runs.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Meta {
-    pub is_processing: bool,
-    pub start_time: Duration,
+    /// Wall clock time of the last successful handler run, since the epoch.
+    pub updated: Duration,
     pub data: HashMap<String, String>,
 }
 
+/// When a module runs next. This only lives as long as the process, so a
+/// restart never inherits a handler that was in flight.
+pub struct Schedule {
+    pub next_run: Instant,
+    pub started: Option<Instant>,
+}
+
+impl Schedule {
+    pub fn new(now: Instant) -> Self {
+        Schedule {
+            next_run: now,
+            started: None,
+        }
+    }
+}
+
+/// How a module treats its saved data at startup.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestorePolicy {
+    /// Render the saved data until the handler has run once.
+    #[default]
+    ShowCachedThenRefresh,
+    /// Start from scratch.
+    Discard,
+    /// Keep the saved data and don't run the handler until its ttl is up.
+    TrustUntilTtl,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Out {
     pub name: String,
     pub instance: String,
     pub full_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub urgent: bool,
+}
+
+/// One block of a module's output. Modules with several blocks give each an
+/// `instance`, which comes back in click events.
+#[derive(Default)]
+pub struct Block {
+    pub instance: Option<String>,
+    pub full_text: String,
+    pub color: Option<String>,
+    pub background: Option<String>,
+    pub border: Option<String>,
+    pub urgent: bool,
+}
+
+impl Block {
+    pub fn text(full_text: String) -> Self {
+        Block {
+            full_text,
+            ..Block::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub click_events: bool,
+    pub stop_signal: i32,
+    pub cont_signal: i32,
+}
+
+/// A click as reported by swaybar on stdin once `click_events` is enabled.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClickEvent {
+    pub name: String,
+    pub instance: String,
+    pub button: u32,
+    pub event: u32,
+    pub x: i32,
+    pub y: i32,
+    pub relative_x: i32,
+    pub relative_y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub modifiers: Vec<String>,
+    /// Set by the click dispatcher when it folds two left clicks into one.
+    #[serde(skip)]
+    pub double_click: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Left,
+    Middle,
+    Right,
+    ScrollUp,
+    ScrollDown,
+    ScrollLeft,
+    ScrollRight,
+    Back,
+    Forward,
+    DoubleClick,
+    Other(u32),
+}
+
+impl ClickEvent {
+    /// Maps the X11 button number swaybar reports onto a named button.
+    pub fn button(&self) -> Button {
+        if self.double_click {
+            return Button::DoubleClick;
+        }
+        match self.button {
+            1 => Button::Left,
+            2 => Button::Middle,
+            3 => Button::Right,
+            4 => Button::ScrollUp,
+            5 => Button::ScrollDown,
+            6 => Button::ScrollLeft,
+            7 => Button::ScrollRight,
+            8 => Button::Back,
+            9 => Button::Forward,
+            n => Button::Other(n),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinAction {
+    Refresh,
+    ToggleIdleInhibit,
+}
+
+/// What a module does when one of its `on_click` bindings matches.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClickAction {
+    Exec(String),
+    Sway(String),
+    Action(BuiltinAction),
+}
+
+pub enum Control {
+    Stop,
+    Cont,
+    Shutdown,
+    Refresh(String),
+    Update(String, HashMap<String, String>),
+    ToggleIdleInhibit,
+    State(StateRequest, oneshot::Sender<StateResponse>),
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// What a new bar does when another one already holds the persist lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstancePolicy {
+    Exit,
+    Wait,
+    #[default]
+    Takeover,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PersistConfig {
     pub path: String,
-    pub buffer_size: i32,
+    pub format: Option<PersistFormat>,
+    pub instance: Option<InstancePolicy>,
+    pub min_interval_ms: Option<u64>,
+    pub max_interval_ms: Option<u64>,
+}
+
+/// Appends selected keys of a module's data to a CSV file every time its
+/// handler finishes.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    pub path: String,
+    pub keys: Vec<String>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<u64>,
+    pub keep: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ModuleConfig {
     pub name: String,
+    #[serde(rename = "type")]
+    pub module_type: Option<String>,
     pub ttl: u64,
     pub timeout: Option<u64>,
     pub display: Option<bool>,
+    pub run_while_hidden: Option<bool>,
+    pub persist: Option<bool>,
+    pub restore: Option<RestorePolicy>,
+    #[serde(default)]
+    pub settings: HashMap<String, String>,
+    #[serde(default)]
+    pub on_click: HashMap<String, ClickAction>,
+    pub history: Option<HistoryConfig>,
+}
+
+impl ModuleConfig {
+    /// The handler this module runs; defaults to the module's name so
+    /// existing configs keep working.
+    pub fn kind(&self) -> &str {
+        self.module_type.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A command to run once there has been no activity for `timeout`
+/// milliseconds, and optionally another one once activity resumes.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdleStage {
+    pub timeout: u64,
+    pub command: String,
+    pub resume_command: Option<String>,
+}
+
+/// Conditions that keep the idle stages from firing.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct InhibitConfig {
+    pub fullscreen: Option<bool>,
+    pub audio: Option<bool>,
+    /// How often to check for playing audio, in milliseconds.
+    pub audio_interval: Option<u64>,
+    #[serde(default)]
+    pub app_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IdleConfig {
+    pub stages: Vec<IdleStage>,
+    #[serde(default)]
+    pub inhibit: InhibitConfig,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub poll_time: Option<u64>,
+    pub click_debounce_time: Option<u64>,
+    pub double_click_time: Option<u64>,
     pub default_timeout: u64,
+    /// Superseded by `idle`, still read as a single suspend stage.
     pub suspend_time: Option<u64>,
+    pub idle: Option<IdleConfig>,
     pub persist: PersistConfig,
     pub modules: Vec<ModuleConfig>,
 }
+
+impl Config {
+    pub fn idle_stages(&self) -> Vec<IdleStage> {
+        match (&self.idle, self.suspend_time) {
+            (Some(idle), _) => idle.stages.clone(),
+            (None, Some(timeout)) => vec![IdleStage {
+                timeout,
+                command: String::from("systemctl suspend"),
+                resume_command: None,
+            }],
+            (None, None) => Vec::new(),
+        }
+    }
+
+    pub fn idle_inhibit(&self) -> InhibitConfig {
+        self.idle
+            .as_ref()
+            .map(|idle| idle.inhibit.clone())
+            .unwrap_or_default()
+    }
+}